@@ -0,0 +1,261 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::tracker::DeliveryStatus;
+
+/// Whether the line ends the response to an AT command.
+pub fn is_final(line: &str) -> bool {
+    let line = line.trim();
+    line == "OK"
+        || line == "ERROR"
+        || line.starts_with("+CME ERROR")
+        || line.starts_with("+CMS ERROR")
+}
+
+/// Signal quality of a `+CSQ: <rssi>,<ber>` line.
+pub fn csq(line: &str) -> Option<i32> {
+    let values = line.trim().strip_prefix("+CSQ:")?;
+    values.split(',').next()?.trim().parse().ok()
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationState {
+    NotRegistered,
+    Home,
+    Searching,
+    Denied,
+    Unknown,
+    Roaming,
+}
+
+impl RegistrationState {
+    fn from_stat(stat: u8) -> Self {
+        match stat {
+            0 => RegistrationState::NotRegistered,
+            1 => RegistrationState::Home,
+            2 => RegistrationState::Searching,
+            3 => RegistrationState::Denied,
+            5 => RegistrationState::Roaming,
+            _ => RegistrationState::Unknown,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AtInfo {
+    Csq {
+        rssi: u8,
+        /// None when the modem does not know the signal strength
+        rssi_dbm: Option<i32>,
+        ber: Option<u8>,
+    },
+    /// Answer to `AT+CREG?`, `AT+CGREG?` or `AT+CEREG?`
+    Registration {
+        name: String,
+        stat: u8,
+        state: RegistrationState,
+        lac: Option<String>,
+        cell_id: Option<String>,
+        act: Option<u8>,
+    },
+    /// Any other `+NAME: values` line, such as `+QENG`
+    Other { name: String, values: Vec<String> },
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AtResponse {
+    pub command: String,
+    /// Lines sent by the device, without the command echo and the result code
+    pub lines: Vec<String>,
+    /// `OK`, `ERROR` or the `+CME ERROR` line, None if the device did not finish answering
+    pub result: Option<String>,
+    pub parsed: Vec<AtInfo>,
+}
+
+impl AtResponse {
+    pub fn is_ok(&self) -> bool {
+        self.result.as_deref() == Some("OK")
+    }
+}
+
+/// Outcome of an AT command sent through the command path.
+#[derive(Serialize, Clone, Debug)]
+pub struct AtResult {
+    pub id: Uuid,
+    pub status: DeliveryStatus,
+    #[serde(flatten)]
+    pub response: AtResponse,
+}
+
+/// Parses the reply collected for `command`, one response line per line.
+pub fn parse(command: &str, reply: &str) -> AtResponse {
+    let mut lines = Vec::new();
+    let mut result = None;
+    for line in reply.lines().map(str::trim) {
+        if line.is_empty() || line.eq_ignore_ascii_case(command.trim()) {
+            continue;
+        }
+        if is_final(line) {
+            result = Some(line.to_string());
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let parsed = lines.iter().filter_map(|line| parse_line(line)).collect();
+    AtResponse {
+        command: command.to_string(),
+        lines,
+        result,
+        parsed,
+    }
+}
+
+fn parse_line(line: &str) -> Option<AtInfo> {
+    let (name, values) = line.strip_prefix('+')?.split_once(':')?;
+    let name = name.trim().to_ascii_uppercase();
+    let values: Vec<String> = values
+        .split(',')
+        .map(|value| value.trim().trim_matches('"').to_string())
+        .collect();
+
+    let info = match name.as_str() {
+        "CSQ" => parse_csq(&values),
+        "CREG" | "CGREG" | "CEREG" => parse_registration(&name, line, &values),
+        _ => None,
+    };
+    info.or(Some(AtInfo::Other { name, values }))
+}
+
+fn parse_csq(values: &[String]) -> Option<AtInfo> {
+    let rssi: u8 = values.first()?.parse().ok()?;
+    let ber: Option<u8> = values.get(1).and_then(|ber| ber.parse().ok());
+    Some(AtInfo::Csq {
+        rssi,
+        // 0 is -113 dBm or less, 31 is -51 dBm or more
+        rssi_dbm: (rssi <= 31).then(|| -113 + 2 * rssi as i32),
+        ber: ber.filter(|ber| *ber <= 7),
+    })
+}
+
+fn parse_registration(name: &str, line: &str, values: &[String]) -> Option<AtInfo> {
+    // Queries answer `n,stat[,lac,ci[,act]]`, unsolicited results leave out `n`
+    let quoted_second = line
+        .split(',')
+        .nth(1)
+        .is_some_and(|v| v.trim().starts_with('"'));
+    let offset = match values.len() {
+        1 => 0,
+        _ if quoted_second => 0,
+        _ => 1,
+    };
+
+    let stat: u8 = values.get(offset)?.parse().ok()?;
+    let optional = |index: usize| {
+        values
+            .get(offset + index)
+            .filter(|value| !value.is_empty())
+            .cloned()
+    };
+    Some(AtInfo::Registration {
+        name: name.to_string(),
+        stat,
+        state: RegistrationState::from_stat(stat),
+        lac: optional(1),
+        cell_id: optional(2),
+        act: optional(3).and_then(|act| act.parse().ok()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_signal_quality() {
+        let response = parse("AT+CSQ", "AT+CSQ\r\n+CSQ: 20,99\r\n\r\nOK\r\n");
+        assert!(response.is_ok());
+        assert_eq!(response.lines, vec!["+CSQ: 20,99"]);
+        let Some(AtInfo::Csq {
+            rssi,
+            rssi_dbm,
+            ber,
+        }) = response.parsed.first().cloned()
+        else {
+            panic!("expected +CSQ, got {:?}", response.parsed);
+        };
+        assert_eq!((rssi, rssi_dbm, ber), (20, Some(-73), None));
+
+        let unknown = parse("AT+CSQ", "+CSQ: 99,99\r\nOK");
+        assert!(matches!(
+            unknown.parsed[0],
+            AtInfo::Csq {
+                rssi: 99,
+                rssi_dbm: None,
+                ber: None
+            }
+        ));
+    }
+
+    #[test]
+    fn parses_registration_queries_and_unsolicited_results() {
+        let response = parse("AT+CREG?", "+CREG: 2,5,\"1A2B\",\"00C3\",7\r\nOK");
+        let Some(AtInfo::Registration {
+            name,
+            stat,
+            state,
+            lac,
+            cell_id,
+            act,
+        }) = response.parsed.first().cloned()
+        else {
+            panic!("expected +CREG, got {:?}", response.parsed);
+        };
+        assert_eq!(
+            (name.as_str(), stat, state),
+            ("CREG", 5, RegistrationState::Roaming)
+        );
+        assert_eq!(lac.as_deref(), Some("1A2B"));
+        assert_eq!(cell_id.as_deref(), Some("00C3"));
+        assert_eq!(act, Some(7));
+
+        let unsolicited = parse("AT+CREG?", "+CREG: 1,\"1A2B\",\"00C3\"\r\nOK");
+        assert!(matches!(
+            &unsolicited.parsed[0],
+            AtInfo::Registration {
+                stat: 1,
+                state: RegistrationState::Home,
+                lac: Some(_),
+                ..
+            }
+        ));
+
+        let query = parse("AT+CREG?", "+CREG: 0,3\r\nOK");
+        assert!(matches!(
+            &query.parsed[0],
+            AtInfo::Registration {
+                stat: 3,
+                state: RegistrationState::Denied,
+                lac: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn stops_at_the_result_code() {
+        let error = parse("AT+CSQ", "ERROR\r\n+CSQ: 20,0");
+        assert_eq!(error.result.as_deref(), Some("ERROR"));
+        assert!(error.lines.is_empty() && !error.is_ok());
+
+        let cme = parse("AT+CPIN?", "AT+CPIN?\r\n+CME ERROR: 10\r\n");
+        assert_eq!(cme.result.as_deref(), Some("+CME ERROR: 10"));
+        assert!(cme.parsed.is_empty());
+
+        let unfinished = parse("AT+QENG", "+QENG: \"servingcell\",\"NOCONN\"");
+        assert_eq!(unfinished.result, None);
+        assert!(matches!(&unfinished.parsed[0], AtInfo::Other { name, .. } if name == "QENG"));
+    }
+}
@@ -1,60 +1,318 @@
-use std::{fmt::Display, str::FromStr};
+use std::fmt::Display;
 
+use anyhow::{Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::selector::DeviceSelector;
+use super::template::TemplateCall;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ClientCommand {
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    /// Every connected device when empty, unless `select` is set
+    #[serde(default)]
     target: Vec<String>,
+    /// Devices added to `target` when the command is dispatched
+    #[serde(default, skip_serializing_if = "DeviceSelector::is_empty")]
+    pub select: DeviceSelector,
+    #[serde(default)]
     pub command: String,
+    #[serde(default, skip_serializing_if = "PayloadEncoding::is_text")]
+    pub encoding: PayloadEncoding,
+    /// Appended to text commands by the text protocol, `\n` unless the device profile sets one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terminator: Option<String>,
+    /// Charset text commands are encoded with, UTF-8 unless the device profile sets one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub charset: Option<String>,
+    #[serde(default, skip_serializing_if = "CommandKind::is_plain")]
+    pub kind: CommandKind,
+    /// Rendered for each target from its firmware's profile, replacing `command`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<TemplateCall>,
+    /// How long the command waits in the outbox of an offline device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_sec: Option<u64>,
+}
+
+/// How `command` is turned into the bytes sent to the device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadEncoding {
+    #[default]
+    Text,
+    Hex,
+    Base64,
+}
+
+impl PayloadEncoding {
+    pub fn is_text(&self) -> bool {
+        *self == PayloadEncoding::Text
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandKind {
+    /// Answered by the first reply of the device
+    #[default]
+    Plain,
+    /// Answered by every line up to the final result code, such as `OK`
+    At,
+}
+
+impl CommandKind {
+    pub fn is_plain(&self) -> bool {
+        *self == CommandKind::Plain
+    }
 }
 
 impl ClientCommand {
     pub fn new(target: Vec<String>, command: String) -> Self {
-        Self { target, command }
+        Self {
+            id: Uuid::new_v4(),
+            target,
+            select: DeviceSelector::default(),
+            command,
+            encoding: PayloadEncoding::Text,
+            terminator: None,
+            charset: None,
+            kind: CommandKind::Plain,
+            template: None,
+            ttl_sec: None,
+        }
     }
 
     pub fn new_broadcast(command: String) -> Self {
         Self {
+            id: Uuid::new_v4(),
             target: Vec::new(),
+            select: DeviceSelector::default(),
             command,
+            encoding: PayloadEncoding::Text,
+            terminator: None,
+            charset: None,
+            kind: CommandKind::Plain,
+            template: None,
+            ttl_sec: None,
         }
     }
 
-    pub fn is_targeted(&self, id: &str) -> bool {
-        if self.target.is_empty() {
-            true
-        } else {
-            self.target.contains(&id.to_string())
-        }
+    /// Empty for commands sent to every device
+    pub fn targets(&self) -> &[String] {
+        &self.target
     }
-}
 
-impl FromStr for ClientCommand {
-    type Err = anyhow::Error;
+    pub fn is_broadcast(&self) -> bool {
+        self.target.is_empty() && self.select.is_empty()
+    }
 
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.splitn(2, ':').collect();
-        let command = if parts.len() == 2 {
-            let targets = parts[0].to_string();
-            let command = parts[1].to_string();
+    /// Copy sent to the devices the selector resolved to, besides the explicit targets.
+    pub fn resolve(&self, selected: Vec<String>) -> Self {
+        let mut command = self.clone();
+        for id in selected {
+            if !command.target.contains(&id) {
+                command.target.push(id);
+            }
+        }
+        command.select = DeviceSelector::default();
+        command
+    }
 
-            let target = targets.split(',').map(|s| s.trim().to_string()).collect();
-            ClientCommand::new(target, command)
-        } else {
-            ClientCommand::new_broadcast(s.to_string())
-        };
+    /// Copy with a new ID, sent to a single device.
+    pub fn for_target(&self, id: &str) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            target: vec![id.to_string()],
+            select: DeviceSelector::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Console form of a command, `targets` are IMEIs and selector items separated by `,`
+    /// or `all`, the payload may be written as `hex(...)` or `base64(...)`.
+    pub fn from_console(targets: &str, command: &str) -> Result<Self> {
+        let mut select = DeviceSelector::default();
+        let mut target = Vec::new();
+        if !targets.trim().eq_ignore_ascii_case("all") {
+            for item in targets.split(',').map(|s| s.trim()) {
+                if !select.parse_item(item)? {
+                    target.push(item.to_string());
+                }
+            }
+        }
 
+        let mut command = parse_payload(ClientCommand::new(target, command.to_string()));
+        command.select = select;
         Ok(command)
     }
+
+    /// Command text, or the template call for templated commands.
+    pub fn summary(&self) -> String {
+        match (&self.template, self.encoding) {
+            (Some(template), _) => template.to_string(),
+            (None, PayloadEncoding::Text) => self.command.clone(),
+            (None, PayloadEncoding::Hex) => format!("hex({})", self.command),
+            (None, PayloadEncoding::Base64) => format!("base64({})", self.command),
+        }
+    }
+
+    /// Bytes of the command, without a terminator.
+    pub fn payload(&self) -> Result<Vec<u8>> {
+        match self.encoding {
+            PayloadEncoding::Text => encode_text(&self.command, self.charset.as_deref()),
+            PayloadEncoding::Hex => decode_hex(&self.command),
+            PayloadEncoding::Base64 => BASE64
+                .decode(self.command.trim())
+                .map_err(|e| anyhow!("invalid base64 payload: {}", e)),
+        }
+    }
+
+    /// Terminator the text protocol appends, binary payloads have none unless one is set.
+    pub fn terminator(&self) -> &str {
+        match (&self.terminator, self.encoding) {
+            (Some(terminator), _) => terminator,
+            (None, PayloadEncoding::Text) => "\n",
+            (None, _) => "",
+        }
+    }
 }
 
 impl Display for ClientCommand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let target = if self.target.is_empty() {
+        let mut target = self.target.clone();
+        if !self.select.is_empty() {
+            target.push(self.select.to_string());
+        }
+        let target = if target.is_empty() {
             "ALL".to_string()
         } else {
-            self.target.join(",")
+            target.join(",")
         };
-        write!(f, "{}:{}", target, self.command)
+        write!(f, "{}:{}", target, self.summary())
+    }
+}
+
+/// Unwraps console payloads written as `hex(...)` or `base64(...)`.
+fn parse_payload(mut command: ClientCommand) -> ClientCommand {
+    let text = command.command.trim();
+    let payload = [
+        ("hex(", PayloadEncoding::Hex),
+        ("base64(", PayloadEncoding::Base64),
+    ]
+    .into_iter()
+    .find_map(|(prefix, encoding)| {
+        let inner = text.strip_prefix(prefix)?.strip_suffix(')')?;
+        Some((inner.to_string(), encoding))
+    });
+
+    if let Some((inner, encoding)) = payload {
+        command.command = inner;
+        command.encoding = encoding;
+    }
+    command
+}
+
+fn encode_text(text: &str, charset: Option<&str>) -> Result<Vec<u8>> {
+    let Some(label) = charset else {
+        return Ok(text.as_bytes().to_vec());
+    };
+
+    let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+        .ok_or(anyhow!("unknown charset {}", label))?;
+    let (bytes, _, unmappable) = encoding.encode(text);
+    if unmappable {
+        bail!("command cannot be encoded as {}", encoding.name());
+    }
+    Ok(bytes.into_owned())
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        bail!("hex payload has an odd number of digits");
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let invalid = || anyhow!("invalid hex payload byte {}", String::from_utf8_lossy(pair));
+            // `from_str_radix` would also accept a sign, such as `+1`
+            if !pair.iter().all(u8::is_ascii_hexdigit) {
+                return Err(invalid());
+            }
+            let pair = std::str::from_utf8(pair)?;
+            u8::from_str_radix(pair, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(text: &str, encoding: PayloadEncoding, charset: Option<&str>) -> ClientCommand {
+        let mut command = ClientCommand::new(vec!["861001".to_string()], text.to_string());
+        command.encoding = encoding;
+        command.charset = charset.map(str::to_string);
+        command
+    }
+
+    #[test]
+    fn decodes_hex_payloads() {
+        let payload = command("7E 01 02\n7e", PayloadEncoding::Hex, None).payload();
+        assert_eq!(payload.unwrap(), vec![0x7E, 0x01, 0x02, 0x7E]);
+    }
+
+    #[test]
+    fn refuses_odd_length_and_invalid_hex() {
+        for text in ["7E0", "7G", "+1", "-1", "7E 0"] {
+            assert!(decode_hex(text).is_err(), "{:?}", text);
+        }
+        assert_eq!(
+            decode_hex("7E0").unwrap_err().to_string(),
+            "hex payload has an odd number of digits"
+        );
+        assert_eq!(
+            decode_hex("+1").unwrap_err().to_string(),
+            "invalid hex payload byte +1"
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_base64() {
+        assert!(
+            command("fgECfg=", PayloadEncoding::Base64, None)
+                .payload()
+                .is_err()
+        );
+        let payload = command("fgECfg==", PayloadEncoding::Base64, None).payload();
+        assert_eq!(payload.unwrap(), vec![0x7E, 0x01, 0x02, 0x7E]);
+    }
+
+    #[test]
+    fn encodes_text_with_the_charset() {
+        let payload = command("\u{4e2d}", PayloadEncoding::Text, Some("gbk")).payload();
+        assert_eq!(payload.unwrap(), vec![0xD6, 0xD0]);
+        let payload = command("\u{4e2d}", PayloadEncoding::Text, None).payload();
+        assert_eq!(payload.unwrap(), "\u{4e2d}".as_bytes());
+    }
+
+    #[test]
+    fn refuses_unencodable_text_and_unknown_charsets() {
+        let unencodable = command("\u{4e2d}", PayloadEncoding::Text, Some("windows-1252"));
+        assert_eq!(
+            unencodable.payload().unwrap_err().to_string(),
+            "command cannot be encoded as windows-1252"
+        );
+        let unknown = command("AT", PayloadEncoding::Text, Some("klingon"));
+        assert_eq!(
+            unknown.payload().unwrap_err().to_string(),
+            "unknown charset klingon"
+        );
     }
 }
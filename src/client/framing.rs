@@ -0,0 +1,264 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+/// Returns the total length of the frame at the start of the buffer once it is known.
+pub type FrameLength = fn(&[u8]) -> Result<Option<usize>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Framing {
+    /// Frames end with `\n`, an optional preceding `\r` is stripped.
+    #[default]
+    Newline,
+    /// Like `Newline`, data left unterminated at the end of a read is a frame too,
+    /// for firmware predating framing that sends one message per write.
+    NewlineOrRead,
+    /// Frames start with a big-endian length (1, 2 or 4 bytes) of the payload.
+    LengthPrefixed { length_bytes: u8 },
+    /// Frames are enclosed between a start byte and a stop byte.
+    Delimited { start: u8, stop: u8 },
+    /// Protocol specific framing, not configurable
+    #[serde(skip)]
+    Custom(FrameLength),
+}
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4096;
+
+pub struct FrameDecoder {
+    framing: Framing,
+    max_frame_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new(framing: Framing, max_frame_size: usize) -> Self {
+        Self {
+            framing,
+            max_frame_size,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Removes and returns everything buffered, framed or not.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.framing {
+            Framing::Newline | Framing::NewlineOrRead => self.next_newline_frame(),
+            Framing::LengthPrefixed { length_bytes } => {
+                self.next_length_prefixed_frame(length_bytes)
+            }
+            Framing::Delimited { start, stop } => self.next_delimited_frame(start, stop),
+            Framing::Custom(frame_length) => self.next_custom_frame(frame_length),
+        }
+    }
+
+    fn next_newline_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let Some(end) = self.buffer.iter().position(|&b| b == b'\n') else {
+                self.check_size(self.buffer.len())?;
+                return Ok(None);
+            };
+
+            let mut frame: Vec<u8> = self.buffer.drain(..=end).collect();
+            frame.pop();
+            if frame.last() == Some(&b'\r') {
+                frame.pop();
+            }
+            self.check_size(frame.len())?;
+
+            // Skip blank lines between frames
+            if !frame.is_empty() {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    fn next_length_prefixed_frame(&mut self, length_bytes: u8) -> Result<Option<Vec<u8>>> {
+        let header_len = length_bytes as usize;
+        if !matches!(header_len, 1 | 2 | 4) {
+            bail!("unsupported length prefix of {} bytes", header_len);
+        }
+        if self.buffer.len() < header_len {
+            return Ok(None);
+        }
+
+        let frame_len = self.buffer[..header_len]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        self.check_size(frame_len)?;
+
+        if self.buffer.len() < header_len + frame_len {
+            return Ok(None);
+        }
+
+        let frame = self.buffer[header_len..header_len + frame_len].to_vec();
+        self.buffer.drain(..header_len + frame_len);
+        Ok(Some(frame))
+    }
+
+    fn next_delimited_frame(&mut self, start: u8, stop: u8) -> Result<Option<Vec<u8>>> {
+        loop {
+            // Discard garbage in front of the start byte
+            match self.buffer.iter().position(|&b| b == start) {
+                Some(begin) => {
+                    self.buffer.drain(..begin);
+                }
+                None => {
+                    self.buffer.clear();
+                    return Ok(None);
+                }
+            }
+
+            let Some(end) = self.buffer[1..]
+                .iter()
+                .position(|&b| b == stop)
+                .map(|i| i + 1)
+            else {
+                self.check_size(self.buffer.len() - 1)?;
+                return Ok(None);
+            };
+
+            let frame = self.buffer[1..end].to_vec();
+            if frame.is_empty() && start == stop {
+                // Two adjacent flags: the second one opens the next frame
+                self.buffer.drain(..end);
+                continue;
+            }
+
+            self.buffer.drain(..=end);
+            self.check_size(frame.len())?;
+            if !frame.is_empty() {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    fn next_custom_frame(&mut self, frame_length: FrameLength) -> Result<Option<Vec<u8>>> {
+        let Some(frame_len) = frame_length(&self.buffer)? else {
+            return Ok(None);
+        };
+        self.check_size(frame_len)?;
+
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+        Ok(Some(self.buffer.drain(..frame_len).collect()))
+    }
+
+    fn check_size(&self, len: usize) -> Result<()> {
+        if len > self.max_frame_size {
+            bail!(
+                "frame of {} bytes exceeds max frame size of {} bytes",
+                len,
+                self.max_frame_size
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(decoder: &mut FrameDecoder) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn newline_frames_split_across_reads() {
+        let mut decoder = FrameDecoder::new(Framing::Newline, DEFAULT_MAX_FRAME_SIZE);
+        decoder.extend(b"HEART");
+        assert!(frames(&mut decoder).is_empty());
+        decoder.extend(b"BEAT\r");
+        assert!(frames(&mut decoder).is_empty());
+        decoder.extend(b"\n");
+        assert_eq!(frames(&mut decoder), [b"HEARTBEAT".to_vec()]);
+        assert!(decoder.buffered().is_empty());
+    }
+
+    #[test]
+    fn newline_frames_coalesced_in_one_read() {
+        let mut decoder = FrameDecoder::new(Framing::Newline, DEFAULT_MAX_FRAME_SIZE);
+        decoder.extend(b"first\r\n\n\r\nsecond\nthi");
+        assert_eq!(
+            frames(&mut decoder),
+            [b"first".to_vec(), b"second".to_vec()]
+        );
+        assert_eq!(decoder.take_buffered(), b"thi");
+        assert!(decoder.buffered().is_empty());
+    }
+
+    #[test]
+    fn length_prefixed_frames() {
+        let framing = Framing::LengthPrefixed { length_bytes: 2 };
+        let mut decoder = FrameDecoder::new(framing, DEFAULT_MAX_FRAME_SIZE);
+        decoder.extend(&[0x00, 0x03, b'a']);
+        assert!(frames(&mut decoder).is_empty());
+        decoder.extend(&[b'b', b'c', 0x00, 0x01, b'd', 0x00]);
+        assert_eq!(frames(&mut decoder), [b"abc".to_vec(), b"d".to_vec()]);
+        assert_eq!(decoder.buffered(), [0x00]);
+
+        let framing = Framing::LengthPrefixed { length_bytes: 3 };
+        let mut decoder = FrameDecoder::new(framing, DEFAULT_MAX_FRAME_SIZE);
+        decoder.extend(&[0x00, 0x00, 0x01, b'a']);
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn delimited_frames_with_shared_flag() {
+        let framing = Framing::Delimited {
+            start: 0x7E,
+            stop: 0x7E,
+        };
+        let mut decoder = FrameDecoder::new(framing, DEFAULT_MAX_FRAME_SIZE);
+        // Garbage before the first flag is dropped
+        decoder.extend(&[0xFF, 0x7E, 0x01, 0x02]);
+        assert!(frames(&mut decoder).is_empty());
+        decoder.extend(&[0x7E, 0x7E, 0x03, 0x7E, 0x7E]);
+        assert_eq!(frames(&mut decoder), [vec![0x01, 0x02], vec![0x03]]);
+        assert_eq!(decoder.buffered(), [0x7E]);
+    }
+
+    #[test]
+    fn custom_frames() {
+        // One byte holding the length of the whole frame
+        let framing = Framing::Custom(|buf| Ok(buf.first().map(|&len| len as usize)));
+        let mut decoder = FrameDecoder::new(framing, DEFAULT_MAX_FRAME_SIZE);
+        decoder.extend(&[0x02, b'a', 0x03]);
+        assert_eq!(frames(&mut decoder), [vec![0x02, b'a']]);
+        decoder.extend(b"bc");
+        assert_eq!(frames(&mut decoder), [vec![0x03, b'b', b'c']]);
+    }
+
+    #[test]
+    fn rejects_frames_above_max_size() {
+        let mut decoder = FrameDecoder::new(Framing::Newline, 4);
+        decoder.extend(b"abcde");
+        assert!(decoder.next_frame().is_err());
+
+        let framing = Framing::LengthPrefixed { length_bytes: 1 };
+        let mut decoder = FrameDecoder::new(framing, 4);
+        decoder.extend(&[0x05]);
+        assert!(decoder.next_frame().is_err());
+    }
+}
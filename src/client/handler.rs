@@ -1,60 +1,133 @@
+use std::collections::VecDeque;
 use std::fmt::Display;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::{path::PathBuf, time::Duration};
 
-use anyhow::{Result, anyhow};
-use chrono::Utc;
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
 use tokio::fs::{self, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use uuid::Uuid;
 
-use crate::client::info::RegisteredClientInfo;
+use crate::client::info::{DeviceStatus, RegisteredClientInfo, is_valid_identifier};
 
-use super::command::ClientCommand;
+use super::at;
+use super::command::{ClientCommand, CommandKind};
+use super::framing::{FrameDecoder, Framing};
 use super::info::ClientInfo;
+use super::outbox::Outbox;
+use super::position::Position;
+use super::protocol::{CommandReply, DeviceMessage, DeviceProtocol, Protocol, Registration};
+use super::session::{
+    COMMAND_QUEUE_SIZE, SessionControl, SessionCounters, SessionHandle, SessionRegistry,
+};
+use super::signal::CsqSample;
+use super::tracker::{CommandTracker, DeliveryStatus};
+use crate::settings::{AuthConfig, ListenerConfig, Settings, UnknownDevicePolicy};
+
+/// Byte stream a device is connected over.
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for T {}
 
 pub struct ClientHandler {
-    client: TcpStream,
+    client: Box<dyn ClientStream>,
     client_addr: SocketAddr,
-    command_rx: broadcast::Receiver<ClientCommand>,
-    heartbeat_duration: Duration,
+    session_id: Uuid,
+    connected_at: DateTime<Utc>,
+    counters: Arc<SessionCounters>,
+    sessions: Arc<SessionRegistry>,
+    control_tx: mpsc::Sender<SessionControl>,
+    control_rx: mpsc::Receiver<SessionControl>,
+    command_tx: mpsc::Sender<ClientCommand>,
+    command_rx: mpsc::Receiver<ClientCommand>,
+    commands: Arc<CommandTracker>,
+    outbox: Arc<Outbox>,
+    /// Commands written to the device, oldest first, waiting for an answer
+    pending_replies: VecDeque<PendingReply>,
+    /// Reloaded settings apply to the session from its next heartbeat or login
+    settings: watch::Receiver<Arc<Settings>>,
     output_dir: String,
+    framing: Framing,
+    protocol: Option<Box<dyn DeviceProtocol>>,
+    decoder: FrameDecoder,
 
+    /// Identifier the device's TLS client certificate was issued to
+    certified_identifier: Option<String>,
     client_info: Option<ClientInfo>,
     output_writer: Option<File>,
+    positions_writer: Option<File>,
+    csq_writer: Option<File>,
+    last_csq: Option<CsqSample>,
+}
+
+struct PendingReply {
+    id: Uuid,
+    written_at: Instant,
+    /// Lines of an AT response received so far
+    at_lines: Option<Vec<String>>,
 }
 
 impl ClientHandler {
     pub fn new(
-        client: TcpStream,
+        client: impl ClientStream + 'static,
         client_addr: SocketAddr,
-        command_rx: broadcast::Receiver<ClientCommand>,
-        heartbeat_duration: Duration,
-        output_dir: String,
+        sessions: Arc<SessionRegistry>,
+        commands: Arc<CommandTracker>,
+        outbox: Arc<Outbox>,
+        settings: watch::Receiver<Arc<Settings>>,
+        listener: &ListenerConfig,
     ) -> Self {
+        let protocol = listener.protocol.create(&listener.framing);
+        let framing = protocol.as_ref().map(|p| p.framing()).unwrap_or_default();
+        let decoder = FrameDecoder::new(framing, listener.max_frame_size);
+        let (command_tx, command_rx) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let (control_tx, control_rx) = mpsc::channel(1);
+        let output_dir = settings.borrow().output_dir.clone();
         Self {
-            client,
+            client: Box::new(client),
             client_addr,
+            session_id: Uuid::new_v4(),
+            connected_at: Utc::now(),
+            counters: Arc::new(SessionCounters::default()),
+            sessions,
+            control_tx,
+            control_rx,
+            command_tx,
             command_rx,
-            heartbeat_duration,
+            commands,
+            outbox,
+            pending_replies: VecDeque::new(),
+            settings,
             output_dir,
+            framing: listener.framing.clone(),
+            protocol,
+            decoder,
+            certified_identifier: None,
             client_info: None,
             output_writer: None,
+            positions_writer: None,
+            csq_writer: None,
+            last_csq: None,
         }
     }
 
     pub async fn verify_client(&mut self) -> Result<ClientInfo> {
         let mut received = vec![0u8; 1024];
 
-        let read_result = self.client.read(&mut received).await;
-        self.handle_read_result(read_result, &mut received).await?;
+        // The login frame may arrive split over several reads
+        while self.client_info.is_none() {
+            let read_result = self.client.read(&mut received).await;
+            self.handle_read_result(read_result, &mut received).await?;
+        }
 
-        return self
-            .client_info
+        self.client_info
             .clone()
-            .ok_or(anyhow!("failed to verify client"));
+            .ok_or(anyhow!("failed to verify client"))
     }
 
     pub async fn run(&mut self) {
@@ -63,6 +136,8 @@ impl ClientHandler {
         let mut client_data = vec![0u8; 1024];
 
         loop {
+            let heartbeat_duration =
+                Duration::from_secs(self.settings.borrow_and_update().heartbeat_sec);
             tokio::select! {
                 biased;
 
@@ -73,14 +148,28 @@ impl ClientHandler {
                     }
                 }
 
-                command = self.command_rx.recv() => {
-                    if let Err(e) = self.handle_client_command(command).await {
+                Some(command) = self.command_rx.recv() => {
+                    if let Err(e) = self.send_command(&command).await {
                         warn!(target: "client_handler", "{} disconnected: {}", self, e);
                         break;
                     }
                 }
 
-                _ = tokio::time::sleep(self.heartbeat_duration), if self.heartbeat_duration.as_secs() > 0 => {
+                Some(control) = self.control_rx.recv() => match control {
+                    SessionControl::Kick(reason) => {
+                        warn!(target: "client_handler", "{} kicked: {}", self, reason);
+                        break;
+                    }
+                    SessionControl::Close => {
+                        info!(target: "client_handler", "closing {}, the server is shutting down", self);
+                        break;
+                    }
+                },
+
+                // Restarts the heartbeat timeout with the reloaded `heartbeat_sec`
+                Ok(()) = self.settings.changed() => {}
+
+                _ = tokio::time::sleep(heartbeat_duration), if heartbeat_duration.as_secs() > 0 => {
                     warn!(target: "client_handler", "{} timed out due to inactivity", self);
                     break;
                 }
@@ -95,13 +184,81 @@ impl ClientHandler {
         self.client_info.as_ref().map(|info| info.identifier())
     }
 
-    async fn register(&mut self, data: &str) -> Result<()> {
-        let info = ClientInfo::from_json(&data).unwrap();
+    #[cfg(feature = "tls")]
+    pub fn set_certified_identifier(&mut self, id: String) {
+        self.certified_identifier.replace(id);
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+            .as_ref()
+            .map(|protocol| protocol.protocol())
+            .unwrap_or(Protocol::Auto)
+    }
+
+    /// Answers a registration according to the status of the device and `unknown_devices`,
+    /// handing out a random authentication code. Configured tokens are never sent.
+    async fn answer_register(&mut self, info: &ClientInfo) -> Result<()> {
         let id = info.identifier();
+        let auth = self.settings.borrow().auth.clone();
+        let certified = self
+            .certified_identifier
+            .as_ref()
+            .is_none_or(|certified| *certified == id);
+        let registration = match certified && is_valid_identifier(&id) {
+            true => registration(&auth, info).await?,
+            false => Registration::Refused,
+        };
+
+        let protocol = self
+            .protocol
+            .as_mut()
+            .ok_or(anyhow!("protocol not detected"))?;
+        let response = protocol.encode_register_response(&registration)?;
+        self.write(&response).await?;
+        match registration {
+            Registration::Accepted(_) => {
+                info!(target: "client_handler", "{} handed out an authentication code to {}", self, id);
+            }
+            Registration::AlreadyRegistered => {
+                info!(target: "client_handler", "{} told {} to log in with its configured token", self, id);
+            }
+            Registration::Refused => {
+                warn!(target: "client_handler", "{} refused the registration of {}", self, id);
+            }
+        }
+        Ok(())
+    }
 
-        self.client_info.replace(info.clone());
+    async fn register(&mut self, info: ClientInfo) -> Result<()> {
+        let id = info.identifier();
+        check_identifier(&id)?;
+        if let Some(certified) = &self.certified_identifier
+            && *certified != id
+        {
+            bail!("certificate issued to {} used by {}", certified, id);
+        }
+        let (auth, duplicate_login) = {
+            let settings = self.settings.borrow();
+            (settings.auth.clone(), settings.duplicate_login)
+        };
+        let mut registered_info = authenticate(&auth, &info).await?;
+
+        let session = SessionHandle {
+            id: self.session_id,
+            info: info.clone(),
+            peer: self.client_addr,
+            connected_at: self.connected_at,
+            protocol: self.protocol(),
+            counters: self.counters.clone(),
+            commands: self.command_tx.clone(),
+            control: self.control_tx.clone(),
+        };
+        self.sessions.insert(session, duplicate_login).await?;
+        let csq = info.csq;
+        self.client_info.replace(info);
 
-        let path = log_path(&self.output_dir, &id);
+        let path = log_path(&self.output_dir, &id)?;
         let file = fs::OpenOptions::new()
             .create(true)
             .append(true)
@@ -109,10 +266,28 @@ impl ClientHandler {
             .await?;
         self.output_writer.replace(file);
 
-        let mut registered_info = match RegisteredClientInfo::find(&id).await {
-            Some(info) => info,
-            None => RegisteredClientInfo::create(info.clone()).await,
-        };
+        let path = positions_path(&self.output_dir, &id)?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        self.positions_writer.replace(file);
+
+        let path = csq_path(&self.output_dir, &id)?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        self.csq_writer.replace(file);
+
+        if let Some(sample) = csq.and_then(CsqSample::new).or(self.last_csq) {
+            self.save_csq(sample).await?;
+            registered_info.last_csq = Some(sample);
+        }
+
+        registered_info.protocol = Some(self.protocol());
         registered_info.update_last_seen();
         registered_info.save().await?;
 
@@ -135,68 +310,295 @@ impl ClientHandler {
             return Err(anyhow!("client disconnected"));
         }
 
-        let received = String::from_utf8_lossy(&received[..read_len]);
-        if received == "HEARTBEAT" {
-            debug!(target: "client_handler", "received heartbeat from {}", self);
+        let counters = &self.counters;
+        SessionCounters::add(&counters.bytes_received, read_len as u64);
+        self.decoder.extend(&received[..read_len]);
+        if self.protocol.is_none() && !self.detect_protocol()? {
             return Ok(());
         }
 
-        info!(target: "client_handler", "received from {}: {}", self, received);
-        if let Err(e) = self.handle_received_data(&received).await {
-            error!(target: "client_handler", "failed to handle data from {}: {}", self, e);
-            return Err(e.into());
+        loop {
+            let frame = match self.decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) if self.is_unterminated_frame() => self.decoder.take_buffered(),
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    error!(target: "client_handler", "invalid frame from {}: {}", self, e);
+                    return Err(e);
+                }
+            };
+
+            SessionCounters::add(&self.counters.frames_received, 1);
+            self.handle_frame(&frame).await?;
         }
+    }
 
-        Ok(())
+    fn is_unterminated_frame(&self) -> bool {
+        let buffered = self.decoder.buffered();
+        !buffered.is_empty()
+            && self
+                .protocol
+                .as_ref()
+                .is_some_and(|protocol| protocol.is_unterminated_frame(buffered))
     }
 
-    async fn handle_received_data(&mut self, data: &str) -> Result<()> {
-        if self.client_info.is_none() {
-            return self.register(data).await;
+    fn detect_protocol(&mut self) -> Result<bool> {
+        let detected = match Protocol::detect(self.decoder.buffered()) {
+            Ok(Some(detected)) => detected,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                error!(target: "client_handler", "failed to detect protocol of {}: {}", self, e);
+                return Err(e);
+            }
+        };
+
+        let protocol = detected
+            .create(&self.framing)
+            .ok_or(anyhow!("cannot create {} protocol", detected))?;
+        self.decoder.set_framing(protocol.framing());
+        self.protocol.replace(protocol);
+
+        info!(target: "client_handler", "detected {} protocol for {}", detected, self);
+        Ok(true)
+    }
+
+    async fn handle_frame(&mut self, frame: &[u8]) -> Result<()> {
+        let protocol = self
+            .protocol
+            .as_mut()
+            .ok_or(anyhow!("protocol not detected"))?;
+        let decoded = match protocol.decode(frame) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                error!(target: "client_handler", "failed to decode frame from {}: {}", self, e);
+                return Err(e);
+            }
+        };
+
+        let login = matches!(decoded.message, DeviceMessage::Login(_));
+        let csq = match &decoded.message {
+            DeviceMessage::Report { positions, .. } => decoded
+                .csq
+                .or(positions.iter().rev().find_map(|position| position.csq)),
+            _ => decoded.csq,
+        };
+        match decoded.message {
+            DeviceMessage::Register(info) => self.answer_register(&info).await?,
+            DeviceMessage::Login(info) => self.register(info).await?,
+            DeviceMessage::Heartbeat => {
+                debug!(target: "client_handler", "received heartbeat from {}", self);
+            }
+            DeviceMessage::Report { text, positions } => {
+                info!(target: "client_handler", "received from {}: {}", self, text);
+                if let Err(e) = self.handle_received_data(&text, &positions).await {
+                    error!(target: "client_handler", "failed to handle data from {}: {}", self, e);
+                    return Err(e);
+                }
+            }
         }
 
+        if let Some(sample) = csq.and_then(CsqSample::new) {
+            self.save_csq(sample).await?;
+        }
+        if let Some(ack) = decoded.ack {
+            self.write(&ack).await?;
+        }
+        if let Some(reply) = decoded.reply {
+            self.handle_command_reply(reply).await;
+        }
+        // Only once the login is acknowledged, devices ignore commands before that
+        if login {
+            self.flush_outbox().await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_reply(&mut self, reply: CommandReply) {
+        // Answers to commands that already timed out would be matched to the wrong command
+        let timeout = self.commands.timeout();
+        self.pending_replies
+            .retain(|pending| pending.written_at.elapsed() <= timeout);
+
+        let Some(pending) = self.pending_replies.front_mut() else {
+            return;
+        };
+        let (status, reply) = match (reply, &mut pending.at_lines) {
+            // AT responses span several replies, up to the final result code
+            (CommandReply::Replied(text), Some(lines)) => {
+                lines.extend(text.lines().map(str::to_string));
+                let Some(result) = text.lines().find(|line| at::is_final(line)) else {
+                    return;
+                };
+                let status = match result.trim() {
+                    "OK" => DeliveryStatus::Replied,
+                    _ => DeliveryStatus::Failed,
+                };
+                (status, Some(lines.join("\n")))
+            }
+            (CommandReply::Acknowledged, Some(_)) => return,
+            (CommandReply::Acknowledged, None) => (DeliveryStatus::Acknowledged, None),
+            (CommandReply::Failed(text), _) => (DeliveryStatus::Failed, Some(text)),
+            (CommandReply::Replied(text), None) => (DeliveryStatus::Replied, Some(text)),
+        };
+
+        let Some(PendingReply { id: command_id, .. }) = self.pending_replies.pop_front() else {
+            return;
+        };
+        let Some(id) = self.identifier() else {
+            return;
+        };
+        debug!(target: "client_handler", "command {} to {} {:?}", command_id, self, status);
+        self.commands.update(command_id, &id, status, reply).await;
+    }
+
+    async fn handle_received_data(&mut self, data: &str, positions: &[Position]) -> Result<()> {
+        let Some(writer) = self.output_writer.as_mut() else {
+            return Err(anyhow!("received data before login"));
+        };
+
         let time = Utc::now().to_rfc3339();
         let log_entry = format!("{} {}\n", time, data);
-        let writer = self.output_writer.as_mut().unwrap();
 
         writer.write_all(log_entry.as_bytes()).await?;
         writer.flush().await?;
 
+        for position in positions {
+            self.save_position(position).await?;
+        }
+
         Ok(())
     }
 
-    async fn handle_client_command(
-        &mut self,
-        command_result: Result<ClientCommand, RecvError>,
-    ) -> Result<()> {
-        if self.client_info.is_none() {
+    async fn save_position(&mut self, position: &Position) -> Result<()> {
+        let mut entry = serde_json::to_string(position)?;
+        entry.push('\n');
+
+        let writer = self.positions_writer.as_mut().unwrap();
+        writer.write_all(entry.as_bytes()).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn save_csq(&mut self, sample: CsqSample) -> Result<()> {
+        // Heartbeats may come before the login, the sample is saved once the device logs in
+        let (Some(id), Some(writer)) = (self.identifier(), self.csq_writer.as_mut()) else {
+            self.last_csq = Some(sample);
             return Ok(());
-        }
+        };
 
-        match command_result {
-            Ok(command) => {
-                if !command.is_targeted(&self.identifier().unwrap()) {
-                    return Ok(());
-                }
+        let mut entry = serde_json::to_string(&sample)?;
+        entry.push('\n');
+        writer.write_all(entry.as_bytes()).await?;
+        writer.flush().await?;
 
-                let msg = format!("{}\n", command.command);
-                if let Err(e) = self.client.write_all(msg.as_bytes()).await {
-                    error!(target: "client_handler", "failed to write to {}: {}", self, e);
-                    return Err(e.into());
-                }
+        self.sessions
+            .set_csq(&id, self.session_id, sample.csq)
+            .await;
+        self.last_csq = Some(sample);
+        Ok(())
+    }
+
+    async fn send_command(&mut self, command: &ClientCommand) -> Result<()> {
+        let id = self.identifier().ok_or(anyhow!("client not verified"))?;
+        let protocol = self
+            .protocol
+            .as_mut()
+            .ok_or(anyhow!("protocol not detected"))?;
+        // Commands the protocol cannot encode only fail themselves, write errors disconnect
+        let msg = match protocol.encode_command(command) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!(target: "client_handler", "failed to encode command {} for {}: {}", command.id, self, e);
+                let reply = Some(e.to_string());
+                self.commands
+                    .update(command.id, &id, DeliveryStatus::Failed, reply)
+                    .await;
+                return Ok(());
             }
-            Err(RecvError::Lagged(_)) => {
-                info!(target: "client_handler", "{} lagged", self);
+        };
+        if let Err(e) = self.write(&msg).await {
+            let reply = Some(e.to_string());
+            self.commands
+                .update(command.id, &id, DeliveryStatus::Failed, reply)
+                .await;
+            return Err(e);
+        }
+
+        SessionCounters::add(&self.counters.commands_sent, 1);
+        self.commands
+            .update(command.id, &id, DeliveryStatus::Written, None)
+            .await;
+        let at_lines = (command.kind == CommandKind::At).then(Vec::new);
+        self.pending_replies.push_back(PendingReply {
+            id: command.id,
+            written_at: Instant::now(),
+            at_lines,
+        });
+        Ok(())
+    }
+
+    /// Sends the commands queued while the device was offline, in order.
+    async fn flush_outbox(&mut self) -> Result<()> {
+        let Some(id) = self.identifier() else {
+            return Ok(());
+        };
+
+        let mut items = self.outbox.take(&id).await.into_iter();
+        while let Some(item) = items.next() {
+            if item.is_expired() {
+                self.commands
+                    .update(item.command.id, &id, DeliveryStatus::TimedOut, None)
+                    .await;
+                continue;
             }
-            Err(RecvError::Closed) => {
-                error!(target: "client_handler", "broadcast channel closed");
-                return Err(anyhow!("broadcast channel closed"));
+
+            info!(target: "client_handler", "sending queued command {} to {}", item.command.id, self);
+            if let Err(e) = self.send_command(&item.command).await {
+                // Keep what is left for the next connection
+                if let Err(e) = self.outbox.restore(&id, items.collect()).await {
+                    error!(target: "client_handler", "failed to restore outbox of {}: {}", self, e);
+                }
+                return Err(e);
             }
         }
         Ok(())
     }
 
-    async fn shutdown_client(&mut self) {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        if let Err(e) = self.client.write_all(data).await {
+            error!(target: "client_handler", "failed to write to {}: {}", self, e);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub async fn shutdown_client(&mut self) {
+        if let Some(id) = self.identifier() {
+            self.sessions.remove(&id, self.session_id).await;
+
+            let last_csq = self.last_csq.take();
+            if let Err(e) = RegisteredClientInfo::save_disconnect(&id, last_csq).await {
+                warn!(target: "client_handler", "failed to save registered info of {}: {}", self, e);
+            }
+
+            // Commands routed here before the session was removed go to the session that
+            // replaced it, or wait for the next connection
+            self.command_rx.close();
+            let surviving = self.sessions.get(&id).await;
+            while let Ok(command) = self.command_rx.try_recv() {
+                let command = match &surviving {
+                    Some(session) => match session.commands.try_send(command) {
+                        Ok(()) => continue,
+                        Err(e) => e.into_inner(),
+                    },
+                    None => command,
+                };
+                if let Err(e) = self.outbox.push(&id, &command).await {
+                    warn!(target: "client_handler", "failed to queue command {} for {}: {}", command.id, self, e);
+                }
+            }
+        }
+
         if let Some(writer) = self.output_writer.as_mut() {
             if let Err(e) = writer.shutdown().await {
                 warn!(target: "client_handler", "failed to close output file for {}: {}", self, e);
@@ -205,6 +607,22 @@ impl ClientHandler {
             self.output_writer = None;
         }
 
+        if let Some(writer) = self.positions_writer.as_mut() {
+            if let Err(e) = writer.shutdown().await {
+                warn!(target: "client_handler", "failed to close positions file for {}: {}", self, e);
+            }
+
+            self.positions_writer = None;
+        }
+
+        if let Some(writer) = self.csq_writer.as_mut() {
+            if let Err(e) = writer.shutdown().await {
+                warn!(target: "client_handler", "failed to close signal quality file for {}: {}", self, e);
+            }
+
+            self.csq_writer = None;
+        }
+
         self.client_info = None;
         self.client.shutdown().await.ok();
     }
@@ -213,12 +631,137 @@ impl ClientHandler {
 impl Display for ClientHandler {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.identifier() {
-            Some(id) => write!(f, "client[addr={}, imei={}]", self.client_addr, id),
-            None => write!(f, "client[addr={}]", self.client_addr),
+            Some(id) => write!(
+                f,
+                "client[addr={}, protocol={}, imei={}]",
+                self.client_addr,
+                self.protocol(),
+                id
+            ),
+            None => write!(
+                f,
+                "client[addr={}, protocol={}]",
+                self.client_addr,
+                self.protocol()
+            ),
         }
     }
 }
 
-pub fn log_path(output_dir: &str, id: &str) -> PathBuf {
-    PathBuf::from(output_dir).join(id)
+/// Checks the device token and status, unknown devices are handled
+/// according to `auth.unknown_devices`.
+/// Same checks as [`authenticate`], for devices asking for an authentication code.
+async fn registration(auth: &AuthConfig, info: &ClientInfo) -> Result<Registration> {
+    let id = info.identifier();
+    if let Some(registered_info) = RegisteredClientInfo::find(&id).await {
+        if registered_info.status != DeviceStatus::Approved {
+            return Ok(Registration::Refused);
+        }
+    } else if !auth.tokens.contains_key(&id) {
+        match auth.unknown_devices {
+            UnknownDevicePolicy::Accept => {}
+            UnknownDevicePolicy::Reject => return Ok(Registration::Refused),
+            UnknownDevicePolicy::Quarantine => {
+                let mut registered_info = RegisteredClientInfo::create(info.clone()).await;
+                registered_info.status = DeviceStatus::Quarantined;
+                registered_info.save().await?;
+                warn!(target: "client_handler", "quarantined unknown device {}", id);
+                return Ok(Registration::Refused);
+            }
+        }
+    }
+
+    if auth.tokens.contains_key(&id) {
+        return Ok(Registration::AlreadyRegistered);
+    }
+    Ok(Registration::Accepted(Uuid::new_v4().simple().to_string()))
+}
+
+async fn authenticate(auth: &AuthConfig, info: &ClientInfo) -> Result<RegisteredClientInfo> {
+    let id = info.identifier();
+
+    let token_required = auth.tokens.get(&id);
+    if let Some(token) = token_required
+        && info.token.as_ref() != Some(token)
+    {
+        bail!("invalid token for {}", id);
+    }
+
+    if let Some(registered_info) = RegisteredClientInfo::find(&id).await {
+        return match registered_info.status {
+            DeviceStatus::Approved => Ok(registered_info),
+            DeviceStatus::Quarantined => bail!("{} is quarantined until approved", id),
+            DeviceStatus::Rejected => bail!("{} has been rejected", id),
+        };
+    }
+
+    let mut registered_info = RegisteredClientInfo::create(info.clone()).await;
+    // Devices with a valid token are known even if they never connected
+    if token_required.is_some() {
+        return Ok(registered_info);
+    }
+
+    match auth.unknown_devices {
+        UnknownDevicePolicy::Accept => Ok(registered_info),
+        UnknownDevicePolicy::Reject => bail!("unknown device {}", id),
+        UnknownDevicePolicy::Quarantine => {
+            registered_info.status = DeviceStatus::Quarantined;
+            registered_info.save().await?;
+            warn!(target: "client_handler", "quarantined unknown device {}", id);
+            bail!("{} is quarantined until approved", id)
+        }
+    }
+}
+
+/// Files of the device are only created for valid identifiers, which cannot leave `output_dir`.
+pub fn log_path(output_dir: &str, id: &str) -> Result<PathBuf> {
+    check_identifier(id)?;
+    Ok(PathBuf::from(output_dir).join(id))
+}
+
+pub fn positions_dir(output_dir: &str) -> PathBuf {
+    PathBuf::from(output_dir).join("positions")
+}
+
+pub fn positions_path(output_dir: &str, id: &str) -> Result<PathBuf> {
+    check_identifier(id)?;
+    Ok(positions_dir(output_dir).join(id))
+}
+
+pub fn csq_dir(output_dir: &str) -> PathBuf {
+    PathBuf::from(output_dir).join("csq")
+}
+
+pub fn csq_path(output_dir: &str, id: &str) -> Result<PathBuf> {
+    check_identifier(id)?;
+    Ok(csq_dir(output_dir).join(id))
+}
+
+fn check_identifier(id: &str) -> Result<()> {
+    if !is_valid_identifier(id) {
+        bail!("invalid device identifier {:?}", id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_paths_of_traversing_identifiers() {
+        for id in ["../registered_infos", "a/b", "..", "", "861001\0"] {
+            assert!(log_path("logs", id).is_err(), "{:?}", id);
+            assert!(positions_path("logs", id).is_err(), "{:?}", id);
+            assert!(csq_path("logs", id).is_err(), "{:?}", id);
+        }
+        assert_eq!(
+            log_path("logs", "861001").unwrap(),
+            PathBuf::from("logs/861001")
+        );
+        assert_eq!(
+            csq_path("logs", "861001").unwrap(),
+            PathBuf::from("logs/csq/861001")
+        );
+    }
 }
@@ -5,6 +5,10 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use super::protocol::Protocol;
+use super::signal::CsqSample;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct ClientInfo {
@@ -14,6 +18,9 @@ pub struct ClientInfo {
 
     #[serde(skip_serializing)]
     pub csq: Option<i32>,
+    /// Pre-shared secret checked against `auth.tokens`, never stored
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
 }
 
 impl ClientInfo {
@@ -26,6 +33,11 @@ impl ClientInfo {
     }
 }
 
+/// Identifiers name the files of the device, so they may only hold ASCII letters and digits.
+pub fn is_valid_identifier(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
 impl Display for ClientInfo {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "Client: [{}]", self.identifier())
@@ -38,15 +50,43 @@ pub struct RegisteredClientInfo {
 
     pub name: Option<String>,
     pub tags: Vec<String>,
+    #[serde(default)]
+    pub status: DeviceStatus,
+
+    /// Protocol spoken on the latest connection
+    #[serde(default)]
+    pub protocol: Option<Protocol>,
+    /// Latest signal quality, saved when the device logs in and disconnects
+    #[serde(default)]
+    pub last_csq: Option<CsqSample>,
 
     pub first_seen: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceStatus {
+    #[default]
+    Approved,
+    /// Unknown device waiting to be approved through the REST API
+    Quarantined,
+    Rejected,
+}
+
+/// Serializes access to `registered_infos.json`, devices logging in concurrently
+/// would otherwise read it while it is being rewritten
+static FILE_LOCK: Mutex<()> = Mutex::const_new(());
+
 impl RegisteredClientInfo {
     const FILE_NAME: &str = "registered_infos.json";
 
     pub async fn load() -> Result<Vec<Self>> {
+        let _guard = FILE_LOCK.lock().await;
+        Self::load_unlocked().await
+    }
+
+    async fn load_unlocked() -> Result<Vec<Self>> {
         if !fs::try_exists(Self::FILE_NAME).await.unwrap_or(false) {
             return Ok(Vec::new());
         }
@@ -71,7 +111,10 @@ impl RegisteredClientInfo {
             base_info: info,
             name: None,
             tags: Vec::new(),
-            first_seen: now.clone(),
+            status: DeviceStatus::default(),
+            protocol: None,
+            last_csq: None,
+            first_seen: now,
             last_seen: now,
         }
     }
@@ -85,7 +128,8 @@ impl RegisteredClientInfo {
     }
 
     pub async fn save(&self) -> Result<()> {
-        let mut registered_clients = Self::load().await?;
+        let _guard = FILE_LOCK.lock().await;
+        let mut registered_clients = Self::load_unlocked().await?;
 
         if let Some(pos) = registered_clients.iter().position(|info| info == self) {
             registered_clients[pos] = self.clone();
@@ -97,11 +141,33 @@ impl RegisteredClientInfo {
         Ok(())
     }
 
+    /// Saves when the device was last seen and its latest signal quality once it disconnects.
+    pub async fn save_disconnect(imei: &str, last_csq: Option<CsqSample>) -> Result<()> {
+        let _guard = FILE_LOCK.lock().await;
+        let mut registered_clients = Self::load_unlocked().await?;
+
+        let Some(info) = registered_clients
+            .iter_mut()
+            .find(|info| info.base_info.imei == imei)
+        else {
+            return Ok(());
+        };
+        info.update_last_seen();
+        if last_csq.is_some() {
+            info.last_csq = last_csq;
+        }
+
+        Self::save_all(&registered_clients).await
+    }
+
     async fn save_all(clients: &[Self]) -> Result<()> {
-        let mut file = fs::File::create(Self::FILE_NAME).await?;
+        let temp_path = format!("{}.tmp", Self::FILE_NAME);
+        let mut file = fs::File::create(&temp_path).await?;
 
         let data = serde_json::to_string_pretty(clients)?;
         file.write_all(data.as_bytes()).await?;
+        file.flush().await?;
+        fs::rename(temp_path, Self::FILE_NAME).await?;
         Ok(())
     }
 }
@@ -0,0 +1,221 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Utc};
+
+use super::position::Position;
+
+const KNOTS_TO_KMH: f64 = 1.852;
+
+pub fn is_sentence(data: &str) -> bool {
+    data.starts_with('$')
+}
+
+pub fn parse(sentence: &str) -> Result<Position> {
+    let sentence = sentence.trim();
+    let body = sentence
+        .strip_prefix('$')
+        .ok_or(anyhow!("NMEA sentence must start with '$'"))?;
+
+    let (body, checksum) = body
+        .split_once('*')
+        .ok_or(anyhow!("NMEA sentence has no checksum"))?;
+    // `from_str_radix` would also accept a sign, such as `+A`
+    let expected = Some(checksum)
+        .filter(|checksum| checksum.len() == 2 && checksum.bytes().all(|b| b.is_ascii_hexdigit()))
+        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+        .ok_or(anyhow!("invalid NMEA checksum: {}", checksum))?;
+    let actual = body.bytes().fold(0u8, |acc, b| acc ^ b);
+    if actual != expected {
+        bail!(
+            "NMEA checksum mismatch: expected {:02X}, got {:02X}",
+            expected,
+            actual
+        );
+    }
+
+    let fields: Vec<&str> = body.split(',').collect();
+    let address = fields[0];
+    if address.len() < 5 || !address.is_ascii() {
+        bail!("invalid NMEA address: {}", address);
+    }
+
+    let mut position = Position::new(address, sentence.to_string());
+    match &address[address.len() - 3..] {
+        "RMC" => parse_rmc(&fields, &mut position)?,
+        "GGA" => parse_gga(&fields, &mut position)?,
+        "GSA" => parse_gsa(&fields, &mut position)?,
+        other => bail!("unsupported NMEA sentence: {}", other),
+    }
+    Ok(position)
+}
+
+// $GPRMC,hhmmss.ss,A,llll.ll,a,yyyyy.yy,a,x.x,x.x,ddmmyy,x.x,a*hh
+fn parse_rmc(fields: &[&str], position: &mut Position) -> Result<()> {
+    expect_fields(fields, 10)?;
+
+    position.valid = fields[2] == "A";
+    position.latitude = parse_coordinate(fields[3], fields[4])?;
+    position.longitude = parse_coordinate(fields[5], fields[6])?;
+    position.speed = parse_number::<f64>(fields[7])?.map(|knots| knots * KNOTS_TO_KMH);
+    position.course = parse_number(fields[8])?;
+
+    let time = parse_time(fields[1])?;
+    let date = parse_date(fields[9])?;
+    position.time = time
+        .zip(date)
+        .map(|(time, date)| date.and_time(time).and_utc());
+    Ok(())
+}
+
+// $GPGGA,hhmmss.ss,llll.ll,a,yyyyy.yy,a,x,xx,x.x,x.x,M,x.x,M,x.x,xxxx*hh
+fn parse_gga(fields: &[&str], position: &mut Position) -> Result<()> {
+    expect_fields(fields, 10)?;
+
+    position.latitude = parse_coordinate(fields[2], fields[3])?;
+    position.longitude = parse_coordinate(fields[4], fields[5])?;
+    position.fix_quality = parse_number(fields[6])?;
+    position.satellites = parse_number(fields[7])?;
+    position.hdop = parse_number(fields[8])?;
+    position.altitude = parse_number(fields[9])?;
+    position.valid = position.fix_quality.is_some_and(|q| q > 0);
+
+    // GGA only carries the time of day, so take the date from reception
+    position.time =
+        parse_time(fields[1])?.map(|time| with_reception_date(time, position.received_at));
+    Ok(())
+}
+
+// $GPGSA,a,x,xx,xx,xx,xx,xx,xx,xx,xx,xx,xx,xx,xx,x.x,x.x,x.x*hh
+fn parse_gsa(fields: &[&str], position: &mut Position) -> Result<()> {
+    expect_fields(fields, 18)?;
+
+    let fix_type: Option<u8> = parse_number(fields[2])?;
+    position.valid = fix_type.is_some_and(|t| t >= 2);
+    position.satellites = Some(fields[3..15].iter().filter(|prn| !prn.is_empty()).count() as u8);
+    position.hdop = parse_number(fields[16])?;
+    Ok(())
+}
+
+fn expect_fields(fields: &[&str], count: usize) -> Result<()> {
+    if fields.len() < count {
+        bail!(
+            "NMEA {} has {} fields, expected at least {}",
+            fields[0],
+            fields.len(),
+            count
+        );
+    }
+    Ok(())
+}
+
+fn parse_number<T: std::str::FromStr>(field: &str) -> Result<Option<T>> {
+    if field.is_empty() {
+        return Ok(None);
+    }
+    field
+        .parse()
+        .map(Some)
+        .map_err(|_| anyhow!("invalid NMEA number: {}", field))
+}
+
+// (d)ddmm.mmmm with hemisphere N/S/E/W
+fn parse_coordinate(value: &str, hemisphere: &str) -> Result<Option<f64>> {
+    let Some(value) = parse_number::<f64>(value)? else {
+        return Ok(None);
+    };
+
+    let degrees = (value / 100.0).trunc();
+    let minutes = value - degrees * 100.0;
+    let coordinate = degrees + minutes / 60.0;
+
+    match hemisphere {
+        "N" | "E" => Ok(Some(coordinate)),
+        "S" | "W" => Ok(Some(-coordinate)),
+        other => bail!("invalid NMEA hemisphere: {}", other),
+    }
+}
+
+fn parse_time(field: &str) -> Result<Option<NaiveTime>> {
+    if field.is_empty() {
+        return Ok(None);
+    }
+    NaiveTime::parse_from_str(field, "%H%M%S%.f")
+        .map(Some)
+        .map_err(|_| anyhow!("invalid NMEA time: {}", field))
+}
+
+fn parse_date(field: &str) -> Result<Option<NaiveDate>> {
+    if field.is_empty() {
+        return Ok(None);
+    }
+    NaiveDate::parse_from_str(field, "%d%m%y")
+        .map(Some)
+        .map_err(|_| anyhow!("invalid NMEA date: {}", field))
+}
+
+fn with_reception_date(time: NaiveTime, received_at: DateTime<Utc>) -> DateTime<Utc> {
+    let at = received_at.date_naive().and_time(time).and_utc();
+    // A fix taken just before midnight but received after it belongs to the previous day
+    if at > received_at + chrono::Duration::hours(1) {
+        at.checked_sub_days(Days::new(1)).unwrap_or(at)
+    } else {
+        at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+
+    #[test]
+    fn parses_rmc() {
+        let position = parse(RMC).unwrap();
+        assert!(position.valid);
+        assert!((position.latitude.unwrap() - 48.1173).abs() < 1e-6);
+        assert!((position.longitude.unwrap() - 11.516_666).abs() < 1e-6);
+        assert!((position.speed.unwrap() - 22.4 * KNOTS_TO_KMH).abs() < 1e-9);
+        assert_eq!(
+            position.time.unwrap().to_rfc3339(),
+            "1994-03-23T12:35:19+00:00"
+        );
+    }
+
+    #[test]
+    fn converts_southern_and_western_hemispheres() {
+        let position =
+            parse("$GNRMC,010203.00,A,3345.000,S,07030.000,W,0.0,,010124,,,A*62").unwrap();
+        assert!((position.latitude.unwrap() + 33.75).abs() < 1e-9);
+        assert!((position.longitude.unwrap() + 70.5).abs() < 1e-9);
+
+        let invalid = parse("$GPRMC,123519,A,4807.038,X,01131.000,E,022.4,084.4,230394,003.1,W*7C");
+        assert_eq!(
+            invalid.unwrap_err().to_string(),
+            "invalid NMEA hemisphere: X"
+        );
+    }
+
+    #[test]
+    fn validates_the_checksum() {
+        let mismatch = RMC.replace("*6A", "*6B");
+        assert_eq!(
+            parse(&mismatch).unwrap_err().to_string(),
+            "NMEA checksum mismatch: expected 6B, got 6A"
+        );
+        for checksum in ["", "6", "+6", "6AA", "G1"] {
+            let sentence = RMC.replace("*6A", &format!("*{}", checksum));
+            assert!(parse(&sentence).is_err(), "{:?}", checksum);
+        }
+        assert!(parse(RMC.trim_end_matches("*6A")).is_err());
+    }
+
+    #[test]
+    fn refuses_non_ascii_input() {
+        assert_eq!(
+            parse("$GPRMC\u{e9},1*3C").unwrap_err().to_string(),
+            "invalid NMEA address: GPRMC\u{e9}"
+        );
+        assert!(parse("$GPR\u{e9},1*00").is_err());
+        assert!(parse(&RMC.replace("*6A", "*\u{e9}")).is_err());
+    }
+}
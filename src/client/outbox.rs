@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::command::ClientCommand;
+use super::info::is_valid_identifier;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxItem {
+    pub command: ClientCommand,
+    pub queued_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl OutboxItem {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at < Utc::now())
+    }
+}
+
+/// Commands waiting for offline devices, stored as JSON Lines in `output_dir/outbox/<imei>`.
+pub struct Outbox {
+    dir: PathBuf,
+    // Serializes the read-modify-write cycles on the files
+    lock: Mutex<()>,
+}
+
+impl Outbox {
+    pub fn new(output_dir: &str) -> Self {
+        Self {
+            dir: outbox_dir(output_dir),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn push(&self, imei: &str, command: &ClientCommand) -> Result<()> {
+        let now = Utc::now();
+        let ttl = command.ttl_sec.map(|ttl| TimeDelta::seconds(ttl as i64));
+        let item = OutboxItem {
+            command: command.clone(),
+            queued_at: now,
+            expires_at: ttl.map(|ttl| now + ttl),
+        };
+
+        let mut entry = serde_json::to_string(&item)?;
+        entry.push('\n');
+
+        let path = self.path(imei)?;
+        let _guard = self.lock.lock().await;
+        fs::create_dir_all(&self.dir).await?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(entry.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    pub async fn list(&self, imei: &str) -> Vec<OutboxItem> {
+        let _guard = self.lock.lock().await;
+        self.load(imei).await
+    }
+
+    /// Removes and returns every item queued for the device, oldest first.
+    pub async fn take(&self, imei: &str) -> Vec<OutboxItem> {
+        let _guard = self.lock.lock().await;
+        let items = self.load(imei).await;
+        if !items.is_empty()
+            && let Ok(path) = self.path(imei)
+        {
+            fs::remove_file(path).await.ok();
+        }
+        items
+    }
+
+    /// Puts items back in front of the ones queued since they were taken.
+    pub async fn restore(&self, imei: &str, mut items: Vec<OutboxItem>) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let _guard = self.lock.lock().await;
+        items.extend(self.load(imei).await);
+        fs::create_dir_all(&self.dir).await?;
+        self.save(imei, &items).await
+    }
+
+    pub async fn cancel(&self, imei: &str, id: Uuid) -> Result<Option<OutboxItem>> {
+        let _guard = self.lock.lock().await;
+        let mut items = self.load(imei).await;
+        let Some(pos) = items.iter().position(|item| item.command.id == id) else {
+            return Ok(None);
+        };
+
+        let item = items.remove(pos);
+        self.save(imei, &items).await?;
+        Ok(Some(item))
+    }
+
+    /// File of the device, identifiers are checked so that they cannot leave the outbox directory.
+    fn path(&self, imei: &str) -> Result<PathBuf> {
+        if !is_valid_identifier(imei) {
+            bail!("invalid device identifier {:?}", imei);
+        }
+        Ok(self.dir.join(imei))
+    }
+
+    async fn load(&self, imei: &str) -> Vec<OutboxItem> {
+        let Ok(path) = self.path(imei) else {
+            return Vec::new();
+        };
+        let Ok(content) = fs::read_to_string(path).await else {
+            return Vec::new();
+        };
+
+        content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    }
+
+    async fn save(&self, imei: &str, items: &[OutboxItem]) -> Result<()> {
+        let path = self.path(imei)?;
+        if items.is_empty() {
+            fs::remove_file(path).await?;
+            return Ok(());
+        }
+
+        let mut data = String::new();
+        for item in items {
+            data.push_str(&serde_json::to_string(item)?);
+            data.push('\n');
+        }
+        fs::write(path, data).await?;
+        Ok(())
+    }
+}
+
+fn outbox_dir(output_dir: &str) -> PathBuf {
+    PathBuf::from(output_dir).join("outbox")
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Position {
+    pub received_at: DateTime<Utc>,
+    pub time: Option<DateTime<Utc>>,
+    pub valid: bool,
+
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Meters above mean sea level
+    pub altitude: Option<f64>,
+    /// km/h
+    pub speed: Option<f64>,
+    /// Degrees from true north
+    pub course: Option<f64>,
+
+    pub fix_quality: Option<u8>,
+    pub satellites: Option<u8>,
+    pub hdop: Option<f64>,
+    /// Signal quality reported with the position, 0 to 31
+    pub csq: Option<i32>,
+
+    /// Protocol specific alarm and status bits
+    pub alarm: Option<u32>,
+    pub status: Option<u32>,
+    /// IO elements keyed by their id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub io: BTreeMap<u16, Value>,
+
+    pub source: String,
+    pub raw: String,
+}
+
+impl Position {
+    pub fn new(source: &str, raw: String) -> Self {
+        Self {
+            received_at: Utc::now(),
+            source: source.to_string(),
+            raw,
+            ..Default::default()
+        }
+    }
+
+    pub fn has_alarm(&self) -> bool {
+        self.alarm.is_some_and(|alarm| alarm != 0)
+    }
+}
@@ -0,0 +1,356 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc};
+
+use super::{CommandReply, Decoded, DeviceMessage, DeviceProtocol, Protocol, hex};
+use crate::client::command::ClientCommand;
+use crate::client::framing::Framing;
+use crate::client::info::ClientInfo;
+use crate::client::position::Position;
+
+const SHORT_START: [u8; 2] = [0x78, 0x78];
+const LONG_START: [u8; 2] = [0x79, 0x79];
+const STOP: [u8; 2] = [0x0D, 0x0A];
+
+const LOGIN: u8 = 0x01;
+const LOCATION: u8 = 0x12;
+const STATUS: u8 = 0x13;
+const STRING_INFO: u8 = 0x15;
+const ALARM: u8 = 0x16;
+const COMMAND_REPLY: u8 = 0x21;
+const LOCATION_4G: u8 = 0x22;
+const ALARM_4G: u8 = 0x26;
+const TIME_REQUEST: u8 = 0x8A;
+const SERVER_COMMAND: u8 = 0x80;
+
+// English, as the commands we send are plain ASCII
+const LANGUAGE: [u8; 2] = [0x00, 0x02];
+
+#[derive(Default)]
+pub struct Gt06Protocol {
+    serial: u16,
+}
+
+impl DeviceProtocol for Gt06Protocol {
+    fn protocol(&self) -> Protocol {
+        Protocol::Gt06
+    }
+
+    fn framing(&self) -> Framing {
+        Framing::Custom(frame_length)
+    }
+
+    fn decode(&mut self, frame: &[u8]) -> Result<Decoded> {
+        decode(frame)
+    }
+
+    fn encode_command(&mut self, command: &ClientCommand) -> Result<Vec<u8>> {
+        self.serial = self.serial.wrapping_add(1);
+        encode_command(&command.payload()?, self.serial)
+    }
+}
+
+/// Total length of the packet at the start of `buf`, once its header is available.
+fn frame_length(buf: &[u8]) -> Result<Option<usize>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    match [buf[0], buf[1]] {
+        SHORT_START if buf.len() >= 3 => Ok(Some(2 + 1 + buf[2] as usize + 2)),
+        LONG_START if buf.len() >= 4 => Ok(Some(
+            2 + 2 + u16::from_be_bytes([buf[2], buf[3]]) as usize + 2,
+        )),
+        SHORT_START | LONG_START => Ok(None),
+        start => bail!("invalid GT06 start bits: {}", hex(&start)),
+    }
+}
+
+fn decode(frame: &[u8]) -> Result<Decoded> {
+    let header_len = if frame.starts_with(&LONG_START) { 4 } else { 3 };
+    // start, length, protocol number, serial, crc and stop
+    if frame.len() < header_len + 1 + 2 + 2 + 2 {
+        bail!("GT06 packet too short: {}", hex(frame));
+    }
+    if !frame.ends_with(&STOP) {
+        bail!("invalid GT06 stop bits: {}", hex(frame));
+    }
+
+    let crc_at = frame.len() - 4;
+    let expected = u16::from_be_bytes([frame[crc_at], frame[crc_at + 1]]);
+    let actual = crc_itu(&frame[2..crc_at]);
+    if expected != actual {
+        bail!(
+            "GT06 CRC mismatch: expected {:04X}, got {:04X}",
+            expected,
+            actual
+        );
+    }
+
+    let protocol = frame[header_len];
+    let content = &frame[header_len + 1..crc_at - 2];
+    let serial = u16::from_be_bytes([frame[crc_at - 2], frame[crc_at - 1]]);
+    let raw = hex(frame);
+
+    let decoded = match protocol {
+        LOGIN => {
+            let info = parse_login(content)?;
+            Decoded::with_ack(DeviceMessage::Login(info), response(LOGIN, serial))
+        }
+        STATUS => Decoded::with_ack(DeviceMessage::Heartbeat, response(STATUS, serial)),
+        LOCATION | LOCATION_4G => {
+            let position = parse_gps(content, raw.clone())?;
+            Decoded::new(report(format!("LOCATION {}", raw), vec![position]))
+        }
+        ALARM | ALARM_4G => {
+            let position = parse_gps(content, raw.clone())?;
+            let message = report(format!("ALARM {}", raw), vec![position]);
+            Decoded::with_ack(message, response(protocol, serial))
+        }
+        STRING_INFO => {
+            // length, server flag (4 bytes), content
+            let text = content.get(5..).unwrap_or_default();
+            let text = String::from_utf8_lossy(text.strip_suffix(&LANGUAGE).unwrap_or(text));
+            Decoded::new(report(text.to_string(), Vec::new()))
+                .with_reply(CommandReply::Replied(text.to_string()))
+        }
+        COMMAND_REPLY => {
+            // server flag (4 bytes), encoding, content
+            let text = content.get(5..).unwrap_or_default();
+            let text = String::from_utf8_lossy(text).to_string();
+            Decoded::new(report(text.clone(), Vec::new())).with_reply(CommandReply::Replied(text))
+        }
+        TIME_REQUEST => {
+            let now = Utc::now();
+            let content = [
+                (now.year() % 100) as u8,
+                now.month() as u8,
+                now.day() as u8,
+                now.hour() as u8,
+                now.minute() as u8,
+                now.second() as u8,
+            ];
+            Decoded::with_ack(
+                DeviceMessage::Heartbeat,
+                packet(TIME_REQUEST, &content, serial),
+            )
+        }
+        _ => Decoded::new(report(format!("{:02X} {}", protocol, raw), Vec::new())),
+    };
+    Ok(decoded)
+}
+
+fn encode_command(command: &[u8], serial: u16) -> Result<Vec<u8>> {
+    let server_flag = (serial as u32).to_be_bytes();
+    // The length byte covers the server flag and the command
+    let max_len = u8::MAX as usize - server_flag.len();
+    if command.len() > max_len {
+        bail!(
+            "GT06 commands are limited to {} bytes, got {}",
+            max_len,
+            command.len()
+        );
+    }
+
+    let mut content = Vec::with_capacity(command.len() + 7);
+    content.push((server_flag.len() + command.len()) as u8);
+    content.extend_from_slice(&server_flag);
+    content.extend_from_slice(command);
+    content.extend_from_slice(&LANGUAGE);
+    Ok(packet(SERVER_COMMAND, &content, serial))
+}
+
+fn report(text: String, positions: Vec<Position>) -> DeviceMessage {
+    DeviceMessage::Report { text, positions }
+}
+
+fn parse_login(content: &[u8]) -> Result<ClientInfo> {
+    let imei = content
+        .get(..8)
+        .ok_or(anyhow!("GT06 login packet too short"))?;
+    let imei = hex(imei);
+    // 8 BCD bytes hold 16 digits, the first one pads the 15 digit IMEI
+    let imei = imei.strip_prefix('0').unwrap_or(&imei).to_string();
+
+    let type_id = content.get(8..10).map(hex).unwrap_or_default();
+    Ok(ClientInfo {
+        imei,
+        iccid: String::new(),
+        fver: type_id,
+        csq: None,
+        token: None,
+    })
+}
+
+// date time (6), satellites (1), latitude (4), longitude (4), speed (1), course and status (2)
+fn parse_gps(content: &[u8], raw: String) -> Result<Position> {
+    let gps = content
+        .get(..18)
+        .ok_or(anyhow!("GT06 GPS information too short"))?;
+
+    let mut position = Position::new("GT06", raw);
+    position.time = parse_time(&gps[..6]);
+    position.satellites = Some(gps[6] & 0x0F);
+
+    let course_status = u16::from_be_bytes([gps[16], gps[17]]);
+    let north = course_status & 0x0400 != 0;
+    let west = course_status & 0x0800 != 0;
+    position.valid = course_status & 0x1000 != 0;
+
+    let latitude = u32::from_be_bytes([gps[7], gps[8], gps[9], gps[10]]) as f64 / 1_800_000.0;
+    let longitude = u32::from_be_bytes([gps[11], gps[12], gps[13], gps[14]]) as f64 / 1_800_000.0;
+    position.latitude = Some(if north { latitude } else { -latitude });
+    position.longitude = Some(if west { -longitude } else { longitude });
+    position.speed = Some(gps[15] as f64);
+    position.course = Some((course_status & 0x03FF) as f64);
+    Ok(position)
+}
+
+fn parse_time(bytes: &[u8]) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::from_ymd_opt(2000 + bytes[0] as i32, bytes[1] as u32, bytes[2] as u32)?;
+    let time = date.and_hms_opt(bytes[3] as u32, bytes[4] as u32, bytes[5] as u32)?;
+    Some(time.and_utc())
+}
+
+fn response(protocol: u8, serial: u16) -> Vec<u8> {
+    packet(protocol, &[], serial)
+}
+
+fn packet(protocol: u8, content: &[u8], serial: u16) -> Vec<u8> {
+    // protocol number, content, serial and crc
+    let length = 1 + content.len() + 2 + 2;
+
+    let mut packet = Vec::with_capacity(length + 6);
+    if length <= u8::MAX as usize {
+        packet.extend_from_slice(&SHORT_START);
+        packet.push(length as u8);
+    } else {
+        packet.extend_from_slice(&LONG_START);
+        packet.extend_from_slice(&(length as u16).to_be_bytes());
+    }
+    packet.push(protocol);
+    packet.extend_from_slice(content);
+    packet.extend_from_slice(&serial.to_be_bytes());
+
+    let crc = crc_itu(&packet[2..]);
+    packet.extend_from_slice(&crc.to_be_bytes());
+    packet.extend_from_slice(&STOP);
+    packet
+}
+
+// CRC-16/X-25, called CRC-ITU in the GT06 documentation
+fn crc_itu(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::protocol::unhex;
+
+    // Login and its response are the examples of the GT06 protocol manual
+    const LOGIN_PACKET: &str = "78780D01012345678901234500018CDD0D0A";
+    const LOGIN_RESPONSE: &str = "787805010001D9DC0D0A";
+    const STATUS_PACKET: &str = "78780A134004040001000FDCEE0D0A";
+    const LOCATION_PACKET: &str =
+        "78781F120B081D112E10CC027AC7EB0C46584900148F01CC00287D001FB8000373770D0A";
+
+    fn positions(decoded: Decoded) -> Vec<Position> {
+        match decoded.message {
+            DeviceMessage::Report { positions, .. } => positions,
+            _ => panic!("expected a report"),
+        }
+    }
+
+    #[test]
+    fn crc_itu_matches_check_value() {
+        assert_eq!(crc_itu(b"123456789"), 0x906E);
+    }
+
+    #[test]
+    fn frame_length_of_short_and_long_packets() {
+        let packet = unhex(LOGIN_PACKET);
+        assert_eq!(frame_length(&packet[..2]).unwrap(), None);
+        assert_eq!(frame_length(&packet[..3]).unwrap(), Some(packet.len()));
+        assert_eq!(frame_length(&unhex("797900")).unwrap(), None);
+        assert_eq!(frame_length(&unhex("79790100")).unwrap(), Some(262));
+        assert!(frame_length(&unhex("7879")).is_err());
+    }
+
+    #[test]
+    fn decodes_login() {
+        let decoded = decode(&unhex(LOGIN_PACKET)).unwrap();
+        assert_eq!(decoded.ack, Some(unhex(LOGIN_RESPONSE)));
+        let DeviceMessage::Login(info) = decoded.message else {
+            panic!("expected a login");
+        };
+        assert_eq!(info.imei, "123456789012345");
+    }
+
+    #[test]
+    fn acknowledges_status() {
+        let decoded = decode(&unhex(STATUS_PACKET)).unwrap();
+        assert!(matches!(decoded.message, DeviceMessage::Heartbeat));
+        assert_eq!(decoded.ack, Some(unhex("78780513000F008F0D0A")));
+    }
+
+    #[test]
+    fn decodes_location() {
+        let decoded = decode(&unhex(LOCATION_PACKET)).unwrap();
+        assert!(decoded.ack.is_none());
+
+        let positions = positions(decoded);
+        let position = &positions[0];
+        assert!(position.valid);
+        assert_eq!(
+            position.time,
+            NaiveDate::from_ymd_opt(2011, 8, 29)
+                .and_then(|date| date.and_hms_opt(17, 46, 16))
+                .map(|time| time.and_utc())
+        );
+        assert_eq!(position.satellites, Some(12));
+        assert_eq!(position.latitude, Some(0x027AC7EB as f64 / 1_800_000.0));
+        assert_eq!(position.longitude, Some(0x0C465849 as f64 / 1_800_000.0));
+        assert_eq!(position.speed, Some(0.0));
+        assert_eq!(position.course, Some(143.0));
+    }
+
+    #[test]
+    fn rejects_crc_mismatch() {
+        let mut packet = unhex(LOGIN_PACKET);
+        packet[5] ^= 1;
+        assert!(decode(&packet).is_err());
+    }
+
+    #[test]
+    fn decodes_command_reply() {
+        let decoded = decode(&unhex(
+            "7878152100000001014C61743A4E32332E313131000A93DF0D0A",
+        ))
+        .unwrap();
+        let Some(CommandReply::Replied(text)) = decoded.reply else {
+            panic!("expected a reply");
+        };
+        assert_eq!(text, "Lat:N23.111");
+    }
+
+    #[test]
+    fn encodes_command() {
+        let packet = encode_command(b"DWXX,0#", 1).unwrap();
+        assert_eq!(
+            packet,
+            unhex("787813800B00000001445758582C302300020001B8730D0A")
+        );
+        assert!(encode_command(&[b'A'; 252], 1).is_err());
+    }
+}
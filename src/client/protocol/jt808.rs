@@ -0,0 +1,663 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+
+use super::{CommandReply, Decoded, DeviceMessage, DeviceProtocol, Protocol, Registration, hex};
+use crate::client::command::ClientCommand;
+use crate::client::framing::Framing;
+use crate::client::info::ClientInfo;
+use crate::client::position::Position;
+
+const FLAG: u8 = 0x7E;
+const ESCAPE: u8 = 0x7D;
+
+const TERMINAL_RESPONSE: u16 = 0x0001;
+const HEARTBEAT: u16 = 0x0002;
+const LOGOUT: u16 = 0x0003;
+const REGISTER: u16 = 0x0100;
+const AUTHENTICATE: u16 = 0x0102;
+const LOCATION: u16 = 0x0200;
+const BATCH_LOCATION: u16 = 0x0704;
+
+const PLATFORM_RESPONSE: u16 = 0x8001;
+const REGISTER_RESPONSE: u16 = 0x8100;
+const TEXT_MESSAGE: u16 = 0x8300;
+
+const RESULT_SUCCESS: u8 = 0;
+const RESULT_UNSUPPORTED: u8 = 3;
+
+// Results of the registration response
+const REGISTER_SUCCESS: u8 = 0;
+const REGISTER_TERMINAL_REGISTERED: u8 = 3;
+const REGISTER_TERMINAL_UNKNOWN: u8 = 4;
+
+const BODY_LENGTH_MASK: u16 = 0x03FF;
+const SUBPACKAGE_BIT: u16 = 0x2000;
+const VERSION_BIT: u16 = 0x4000;
+
+// Phone numbers are 6 BCD bytes in the 2013 revision and 10 bytes in the 2019 one
+const PHONE_DIGITS: usize = 12;
+const PHONE_DIGITS_2019: usize = 20;
+
+const STATUS_POSITIONED: u32 = 1 << 1;
+const STATUS_SOUTH: u32 = 1 << 2;
+const STATUS_WEST: u32 = 1 << 3;
+
+const EXTRA_SIGNAL: u8 = 0x30;
+const EXTRA_SATELLITES: u8 = 0x31;
+
+struct Header {
+    id: u16,
+    phone: String,
+    serial: u16,
+    version: Option<u8>,
+}
+
+#[derive(Default)]
+pub struct Jt808Protocol {
+    serial: u16,
+    phone: Option<String>,
+    /// Serial and version of the registration waiting for its response
+    register: Option<(u16, Option<u8>)>,
+    /// Model the terminal registered with, authentication does not repeat it
+    model: Option<String>,
+}
+
+impl Jt808Protocol {
+    fn next_serial(&mut self) -> u16 {
+        self.serial = self.serial.wrapping_add(1);
+        self.serial
+    }
+}
+
+impl DeviceProtocol for Jt808Protocol {
+    fn protocol(&self) -> Protocol {
+        Protocol::Jt808
+    }
+
+    fn framing(&self) -> Framing {
+        Framing::Delimited {
+            start: FLAG,
+            stop: FLAG,
+        }
+    }
+
+    fn decode(&mut self, frame: &[u8]) -> Result<Decoded> {
+        let serial = self.next_serial();
+        let (header, mut decoded) = decode(frame, serial)?;
+        match &mut decoded.message {
+            DeviceMessage::Register(info) => {
+                self.register.replace((header.serial, header.version));
+                self.model.replace(info.fver.clone());
+            }
+            DeviceMessage::Login(info) if info.fver.is_empty() => {
+                info.fver = self.model.clone().unwrap_or_default();
+            }
+            _ => {}
+        }
+        self.phone.replace(header.phone);
+        Ok(decoded)
+    }
+
+    fn encode_command(&mut self, command: &ClientCommand) -> Result<Vec<u8>> {
+        let phone = self
+            .phone
+            .clone()
+            .ok_or(anyhow!("JT/T 808 terminal has not identified itself"))?;
+        let serial = self.next_serial();
+        encode_command(&phone, &command.payload()?, serial)
+    }
+
+    fn encode_register_response(&mut self, registration: &Registration) -> Result<Vec<u8>> {
+        let (register_serial, version) = self
+            .register
+            .take()
+            .ok_or(anyhow!("no JT/T 808 registration to answer"))?;
+        let phone = self
+            .phone
+            .clone()
+            .ok_or(anyhow!("JT/T 808 terminal has not identified itself"))?;
+
+        let mut body = register_serial.to_be_bytes().to_vec();
+        // The authentication code only follows a successful result
+        match registration {
+            Registration::Accepted(auth_code) => {
+                body.push(REGISTER_SUCCESS);
+                body.extend_from_slice(auth_code.as_bytes());
+            }
+            Registration::AlreadyRegistered => body.push(REGISTER_TERMINAL_REGISTERED),
+            Registration::Refused => body.push(REGISTER_TERMINAL_UNKNOWN),
+        }
+        let serial = self.next_serial();
+        encode(REGISTER_RESPONSE, &phone, version, serial, &body)
+    }
+}
+
+/// Decodes a message without its 0x7E flags, `serial` numbers the platform reply.
+fn decode(frame: &[u8], serial: u16) -> Result<(Header, Decoded)> {
+    let data = unescape(frame)?;
+    let (&checksum, data) = data.split_last().ok_or(anyhow!("empty JT/T 808 message"))?;
+    let actual = data.iter().fold(0u8, |acc, b| acc ^ b);
+    if checksum != actual {
+        bail!(
+            "JT/T 808 checksum mismatch: expected {:02X}, got {:02X}",
+            checksum,
+            actual
+        );
+    }
+
+    let (header, body) = parse_header(data)?;
+    let raw = hex(frame);
+    let ack = |result| platform_response(&header, serial, result);
+
+    let decoded = match header.id {
+        // Answered through `encode_register_response` once the device is checked
+        REGISTER => Decoded::new(DeviceMessage::Register(parse_register(&header, body)?)),
+        AUTHENTICATE => {
+            let info = parse_authenticate(&header, body);
+            Decoded::with_ack(DeviceMessage::Login(info), ack(RESULT_SUCCESS)?)
+        }
+        HEARTBEAT => Decoded::with_ack(DeviceMessage::Heartbeat, ack(RESULT_SUCCESS)?),
+        LOGOUT => Decoded::with_ack(
+            report(format!("LOGOUT {}", raw), Vec::new()),
+            ack(RESULT_SUCCESS)?,
+        ),
+        LOCATION => {
+            let position = parse_location(body, raw.clone())?;
+            let message = report(format!("LOCATION {}", raw), vec![position]);
+            Decoded::with_ack(message, ack(RESULT_SUCCESS)?)
+        }
+        BATCH_LOCATION => {
+            let positions = parse_batch_location(body, &raw)?;
+            let message = report(format!("BATCH_LOCATION {}", raw), positions);
+            Decoded::with_ack(message, ack(RESULT_SUCCESS)?)
+        }
+        TERMINAL_RESPONSE => {
+            let body = body
+                .get(..5)
+                .ok_or(anyhow!("JT/T 808 terminal response too short"))?;
+            let id = u16::from_be_bytes([body[2], body[3]]);
+            let result = body[4];
+            let text = format!(
+                "RESPONSE serial={} id={:04X} result={}",
+                u16::from_be_bytes([body[0], body[1]]),
+                id,
+                result
+            );
+
+            let decoded = Decoded::new(report(text.clone(), Vec::new()));
+            match (id, result) {
+                (TEXT_MESSAGE, RESULT_SUCCESS) => decoded.with_reply(CommandReply::Acknowledged),
+                (TEXT_MESSAGE, _) => decoded.with_reply(CommandReply::Failed(text)),
+                _ => decoded,
+            }
+        }
+        id => {
+            let message = report(format!("{:04X} {}", id, raw), Vec::new());
+            Decoded::with_ack(message, ack(RESULT_UNSUPPORTED)?)
+        }
+    };
+    Ok((header, decoded))
+}
+
+/// Encodes a command as a text message (0x8300) to the terminal identified by `phone`.
+fn encode_command(phone: &str, command: &[u8], serial: u16) -> Result<Vec<u8>> {
+    let version = (phone.len() > PHONE_DIGITS).then_some(1);
+
+    // Show on the terminal display
+    let mut body = vec![0x04];
+    if version.is_some() {
+        // Text type: notification
+        body.push(0x01);
+    }
+    body.extend_from_slice(command);
+    encode(TEXT_MESSAGE, phone, version, serial, &body)
+}
+
+fn report(text: String, positions: Vec<Position>) -> DeviceMessage {
+    DeviceMessage::Report { text, positions }
+}
+
+fn parse_header(data: &[u8]) -> Result<(Header, &[u8])> {
+    let too_short = || anyhow!("JT/T 808 message too short: {}", hex(data));
+
+    let fixed = data.get(..4).ok_or_else(too_short)?;
+    let id = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let properties = u16::from_be_bytes([fixed[2], fixed[3]]);
+
+    let mut offset = 4;
+    let version = if properties & VERSION_BIT != 0 {
+        offset += 1;
+        Some(data.get(4).copied().ok_or_else(too_short)?)
+    } else {
+        None
+    };
+    let phone_len = if version.is_some() {
+        PHONE_DIGITS_2019 / 2
+    } else {
+        PHONE_DIGITS / 2
+    };
+    let phone = data.get(offset..offset + phone_len).ok_or_else(too_short)?;
+    let phone = hex(phone);
+    offset += phone_len;
+
+    let serial = data.get(offset..offset + 2).ok_or_else(too_short)?;
+    let serial = u16::from_be_bytes([serial[0], serial[1]]);
+    offset += 2;
+    if properties & SUBPACKAGE_BIT != 0 {
+        // Total package count and package index
+        offset += 4;
+    }
+
+    let body_len = (properties & BODY_LENGTH_MASK) as usize;
+    let body = data.get(offset..offset + body_len).ok_or_else(too_short)?;
+
+    let header = Header {
+        id,
+        phone,
+        serial,
+        version,
+    };
+    Ok((header, body))
+}
+
+// province (2), city (2), manufacturer, model, terminal id, plate color (1), plate
+fn parse_register(header: &Header, body: &[u8]) -> Result<ClientInfo> {
+    let (manufacturer_len, model_len) = if header.version.is_some() {
+        (11, 30)
+    } else {
+        (5, 20)
+    };
+    let model = body
+        .get(4 + manufacturer_len..4 + manufacturer_len + model_len)
+        .ok_or(anyhow!("JT/T 808 registration too short"))?;
+
+    Ok(ClientInfo {
+        imei: header.phone.clone(),
+        iccid: String::new(),
+        fver: trim_string(model),
+        csq: None,
+        token: None,
+    })
+}
+
+// 2013: authentication code
+// 2019: code length (1), code, IMEI (15), software version (20)
+fn parse_authenticate(header: &Header, body: &[u8]) -> ClientInfo {
+    let (code, fver) = match header.version {
+        Some(_) => {
+            let code_len = body.first().copied().unwrap_or_default() as usize;
+            let code = body.get(1..1 + code_len).map(trim_string);
+            let version_at = 1 + code_len + 15;
+            let fver = body
+                .get(version_at..version_at + 20)
+                .map(trim_string)
+                .unwrap_or_default();
+            (code, fver)
+        }
+        None => (Some(trim_string(body)), String::new()),
+    };
+
+    // The authentication code doubles as the device token
+    ClientInfo {
+        imei: header.phone.clone(),
+        iccid: String::new(),
+        fver,
+        csq: None,
+        token: code.filter(|code| !code.is_empty()),
+    }
+}
+
+// alarm (4), status (4), latitude (4), longitude (4), altitude (2), speed (2), direction (2),
+// time (6), then additional items of id (1), length (1), value
+fn parse_location(body: &[u8], raw: String) -> Result<Position> {
+    if body.len() < 28 {
+        bail!("JT/T 808 location report too short: {}", hex(body));
+    }
+    let u32_at = |i: usize| u32::from_be_bytes([body[i], body[i + 1], body[i + 2], body[i + 3]]);
+    let u16_at = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+
+    let mut position = Position::new("JT808", raw);
+    let alarm = u32_at(0);
+    let status = u32_at(4);
+    position.alarm = Some(alarm);
+    position.status = Some(status);
+    position.valid = status & STATUS_POSITIONED != 0;
+
+    let latitude = u32_at(8) as f64 / 1_000_000.0;
+    let longitude = u32_at(12) as f64 / 1_000_000.0;
+    position.latitude = Some(if status & STATUS_SOUTH != 0 {
+        -latitude
+    } else {
+        latitude
+    });
+    position.longitude = Some(if status & STATUS_WEST != 0 {
+        -longitude
+    } else {
+        longitude
+    });
+    position.altitude = Some(u16_at(16) as f64);
+    position.speed = Some(u16_at(18) as f64 / 10.0);
+    position.course = Some(u16_at(20) as f64);
+    position.time = parse_time(&body[22..28]);
+
+    let mut extra = &body[28..];
+    while let [id, len, rest @ ..] = extra {
+        let Some(value) = rest.get(..*len as usize) else {
+            break;
+        };
+        match (*id, value) {
+            (EXTRA_SIGNAL, [signal]) => position.csq = Some(*signal as i32),
+            (EXTRA_SATELLITES, [satellites]) => position.satellites = Some(*satellites),
+            _ => {}
+        }
+        extra = &rest[*len as usize..];
+    }
+    Ok(position)
+}
+
+// count (2), type (1), then items of length (2) and location report
+fn parse_batch_location(body: &[u8], raw: &str) -> Result<Vec<Position>> {
+    let count = body
+        .get(..2)
+        .map(|count| u16::from_be_bytes([count[0], count[1]]))
+        .ok_or(anyhow!("JT/T 808 batch location too short"))?;
+
+    let mut positions = Vec::with_capacity(count as usize);
+    let mut items = body.get(3..).unwrap_or_default();
+    for _ in 0..count {
+        let [high, low, rest @ ..] = items else {
+            break;
+        };
+        let len = u16::from_be_bytes([*high, *low]) as usize;
+        let item = rest
+            .get(..len)
+            .ok_or(anyhow!("JT/T 808 batch location item too short"))?;
+        positions.push(parse_location(item, raw.to_string())?);
+        items = &rest[len..];
+    }
+    Ok(positions)
+}
+
+// BCD YYMMDDhhmmss in GMT+8
+fn parse_time(bcd: &[u8]) -> Option<DateTime<Utc>> {
+    let digits: Vec<u32> = bcd
+        .iter()
+        .map(|b| (b >> 4) as u32 * 10 + (b & 0x0F) as u32)
+        .collect();
+    let time = NaiveDate::from_ymd_opt(2000 + digits[0] as i32, digits[1], digits[2])?
+        .and_hms_opt(digits[3], digits[4], digits[5])?;
+    let beijing = FixedOffset::east_opt(8 * 3600)?;
+    Some(
+        time.and_local_timezone(beijing)
+            .single()?
+            .with_timezone(&Utc),
+    )
+}
+
+fn trim_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string()
+}
+
+fn platform_response(header: &Header, serial: u16, result: u8) -> Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(5);
+    body.extend_from_slice(&header.serial.to_be_bytes());
+    body.extend_from_slice(&header.id.to_be_bytes());
+    body.push(result);
+    encode(
+        PLATFORM_RESPONSE,
+        &header.phone,
+        header.version,
+        serial,
+        &body,
+    )
+}
+
+fn encode(id: u16, phone: &str, version: Option<u8>, serial: u16, body: &[u8]) -> Result<Vec<u8>> {
+    // Longer bodies have to be split into packages, which we do not send
+    if body.len() > BODY_LENGTH_MASK as usize {
+        bail!(
+            "JT/T 808 message bodies are limited to {} bytes, got {}",
+            BODY_LENGTH_MASK,
+            body.len()
+        );
+    }
+
+    let mut properties = body.len() as u16;
+    if version.is_some() {
+        properties |= VERSION_BIT;
+    }
+
+    let mut data = Vec::with_capacity(body.len() + 18);
+    data.extend_from_slice(&id.to_be_bytes());
+    data.extend_from_slice(&properties.to_be_bytes());
+    let phone_digits = match version {
+        Some(version) => {
+            data.push(version);
+            PHONE_DIGITS_2019
+        }
+        None => PHONE_DIGITS,
+    };
+    data.extend_from_slice(&encode_bcd(phone, phone_digits));
+    data.extend_from_slice(&serial.to_be_bytes());
+    data.extend_from_slice(body);
+    data.push(data.iter().fold(0u8, |acc, b| acc ^ b));
+
+    let mut message = Vec::with_capacity(data.len() + 4);
+    message.push(FLAG);
+    for byte in data {
+        match byte {
+            FLAG => message.extend_from_slice(&[ESCAPE, 0x02]),
+            ESCAPE => message.extend_from_slice(&[ESCAPE, 0x01]),
+            byte => message.push(byte),
+        }
+    }
+    message.push(FLAG);
+    Ok(message)
+}
+
+fn encode_bcd(digits: &str, len: usize) -> Vec<u8> {
+    let digits = format!("{:0>len$}", digits, len = len);
+    let nibble = |c: u8| (c as char).to_digit(16).unwrap_or_default() as u8;
+    digits
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| (nibble(pair[0]) << 4) | nibble(pair[1]))
+        .collect()
+}
+
+fn unescape(frame: &[u8]) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(frame.len());
+    let mut bytes = frame.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != ESCAPE {
+            data.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(0x01) => data.push(ESCAPE),
+            Some(0x02) => data.push(FLAG),
+            other => bail!("invalid JT/T 808 escape sequence: 7D {:02X?}", other),
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::protocol::unhex;
+
+    // 2013 revision location report, its serial 0x007E is escaped
+    const LOCATION_MESSAGE: &str = "02000026123456789012007D02000000010000000200BA7F0E07E4F11C0028003C00001810151010100104000000640202003759";
+    const HEARTBEAT_2019: &str = "000240000100000000013912345678000576";
+    const REGISTER_MESSAGE: &str = "010000290139123456780003002C0064414243444547542D38303800000000000000000000000000003132333435363701544553543B";
+    const AUTHENTICATE_MESSAGE: &str = "0102000701391234567800044155544831323308";
+
+    /// Adds the flags the framing strips.
+    fn flagged(hex: &str) -> Vec<u8> {
+        unhex(&format!("7E{}7E", hex))
+    }
+
+    #[test]
+    fn escapes_and_unescapes_flags() {
+        assert_eq!(
+            unescape(&unhex("307D02087D0155")).unwrap(),
+            unhex("307E087D55")
+        );
+        assert!(unescape(&unhex("307D03")).is_err());
+        assert!(unescape(&unhex("307D")).is_err());
+
+        let message = encode(0x7E7D, "013912345678", None, 0x7E, &[]).unwrap();
+        assert_eq!(message, flagged("7D027D010000013912345678007D024D"));
+    }
+
+    #[test]
+    fn parses_bcd_time_in_beijing_time() {
+        assert_eq!(
+            parse_time(&unhex("181015101010")),
+            NaiveDate::from_ymd_opt(2018, 10, 15)
+                .and_then(|date| date.and_hms_opt(2, 10, 10))
+                .map(|time| time.and_utc())
+        );
+        assert_eq!(parse_time(&unhex("181315101010")), None);
+    }
+
+    #[test]
+    fn encodes_bcd_phone_numbers() {
+        assert_eq!(
+            encode_bcd("13912345678", PHONE_DIGITS),
+            unhex("013912345678")
+        );
+        assert_eq!(
+            encode_bcd("13912345678", PHONE_DIGITS_2019),
+            unhex("00000000013912345678")
+        );
+    }
+
+    #[test]
+    fn decodes_location() {
+        let (header, decoded) = decode(&unhex(LOCATION_MESSAGE), 1).unwrap();
+        assert_eq!(header.phone, "123456789012");
+        assert_eq!(header.serial, 0x007E);
+        assert_eq!(
+            decoded.ack,
+            Some(flagged("800100051234567890120001007D0202000073"))
+        );
+
+        let DeviceMessage::Report { positions, .. } = decoded.message else {
+            panic!("expected a report");
+        };
+        let position = &positions[0];
+        assert!(position.valid);
+        assert_eq!(position.alarm, Some(1));
+        assert_eq!(position.latitude, Some(12.222222));
+        assert_eq!(position.longitude, Some(132.444444));
+        assert_eq!(position.altitude, Some(40.0));
+        assert_eq!(position.speed, Some(6.0));
+        assert_eq!(position.course, Some(0.0));
+        assert_eq!(position.time, parse_time(&unhex("181015101010")));
+    }
+
+    #[test]
+    fn rejects_truncated_2019_header() {
+        assert!(decode(&unhex("0002400042"), 1).is_err());
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let mut message = unhex(LOCATION_MESSAGE);
+        let last = message.len() - 1;
+        message[last] ^= 1;
+        assert!(decode(&message, 1).is_err());
+    }
+
+    #[test]
+    fn acknowledges_2019_heartbeat() {
+        let (header, decoded) = decode(&unhex(HEARTBEAT_2019), 1).unwrap();
+        assert_eq!(header.version, Some(1));
+        assert!(matches!(decoded.message, DeviceMessage::Heartbeat));
+        assert_eq!(
+            decoded.ack,
+            Some(flagged("80014005010000000001391234567800010005000200F3"))
+        );
+    }
+
+    #[test]
+    fn registers_and_authenticates() {
+        let mut protocol = Jt808Protocol::default();
+        let decoded = protocol.decode(&unhex(REGISTER_MESSAGE)).unwrap();
+        assert!(decoded.ack.is_none());
+        let DeviceMessage::Register(info) = decoded.message else {
+            panic!("expected a registration");
+        };
+        assert_eq!(info.imei, "013912345678");
+        assert_eq!(info.fver, "GT-808");
+
+        assert_eq!(
+            protocol
+                .encode_register_response(&Registration::Accepted("AUTH123".to_string()))
+                .unwrap(),
+            flagged("8100000A01391234567800020003004155544831323382")
+        );
+        let accepted = Registration::Accepted("AUTH123".to_string());
+        assert!(protocol.encode_register_response(&accepted).is_err());
+
+        let decoded = protocol.decode(&unhex(AUTHENTICATE_MESSAGE)).unwrap();
+        assert_eq!(
+            decoded.ack,
+            Some(flagged("8001000501391234567800030004010200B0"))
+        );
+        let DeviceMessage::Login(info) = decoded.message else {
+            panic!("expected a login");
+        };
+        assert_eq!(info.token.as_deref(), Some("AUTH123"));
+        assert_eq!(info.fver, "GT-808");
+    }
+
+    #[test]
+    fn refuses_registrations_without_a_code() {
+        for (registration, response) in [
+            (Registration::Refused, "810000030139123456780002000304B7"),
+            (
+                Registration::AlreadyRegistered,
+                "810000030139123456780002000303B0",
+            ),
+        ] {
+            let mut protocol = Jt808Protocol::default();
+            protocol.decode(&unhex(REGISTER_MESSAGE)).unwrap();
+            assert_eq!(
+                protocol.encode_register_response(&registration).unwrap(),
+                flagged(response)
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_text_message_responses() {
+        let (_, decoded) = decode(&unhex("0001000501391234567800090001830000BF"), 1).unwrap();
+        assert!(matches!(decoded.reply, Some(CommandReply::Acknowledged)));
+
+        let (_, decoded) = decode(&unhex("0001000501391234567800090001830001BE"), 1).unwrap();
+        assert!(matches!(decoded.reply, Some(CommandReply::Failed(_))));
+    }
+
+    #[test]
+    fn encodes_text_message_commands() {
+        assert_eq!(
+            encode_command("013912345678", b"HELLO", 1).unwrap(),
+            flagged("8300000601391234567800010448454C4C4FF2")
+        );
+        assert_eq!(
+            encode_command("00000000013912345678", b"HELLO", 2).unwrap(),
+            flagged("8300400701000000000139123456780002040148454C4C4FB0")
+        );
+    }
+
+    #[test]
+    fn refuses_bodies_longer_than_the_length_field() {
+        assert!(encode_command("013912345678", &[b'A'; 1022], 1).is_ok());
+        assert!(encode_command("013912345678", &[b'A'; 1023], 1).is_err());
+    }
+}
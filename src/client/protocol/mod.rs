@@ -0,0 +1,175 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+use super::command::ClientCommand;
+use super::framing::Framing;
+use super::info::ClientInfo;
+use super::position::Position;
+
+pub mod gt06;
+pub mod jt808;
+pub mod teltonika;
+pub mod text;
+
+/// Everything a [`ClientHandler`](super::handler::ClientHandler) needs to know
+/// about the wire format spoken by a device.
+pub trait DeviceProtocol: Send {
+    fn protocol(&self) -> Protocol;
+
+    /// How the byte stream is split into frames passed to [`DeviceProtocol::decode`].
+    fn framing(&self) -> Framing;
+
+    /// Identifies logins and heartbeats, and builds the ack the device expects.
+    fn decode(&mut self, frame: &[u8]) -> Result<Decoded>;
+
+    /// Whether bytes left unframed at the end of a read already form a whole message,
+    /// for devices that do not terminate their messages.
+    fn is_unterminated_frame(&self, _buffered: &[u8]) -> bool {
+        false
+    }
+
+    fn encode_command(&mut self, command: &ClientCommand) -> Result<Vec<u8>>;
+
+    /// Answers the last [`DeviceMessage::Register`].
+    fn encode_register_response(&mut self, _registration: &Registration) -> Result<Vec<u8>> {
+        bail!("{} devices do not register", self.protocol())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// JSON login followed by newline separated text
+    #[default]
+    Text,
+    Gt06,
+    Jt808,
+    Teltonika,
+    /// Detected from the first bytes sent by the device
+    Auto,
+}
+
+impl Protocol {
+    /// `framing` only applies to protocols without a framing of their own,
+    /// nothing is created for [`Protocol::Auto`] until it is detected.
+    pub fn create(&self, framing: &Framing) -> Option<Box<dyn DeviceProtocol>> {
+        let protocol: Box<dyn DeviceProtocol> = match self {
+            Protocol::Text => Box::new(text::TextProtocol::new(framing.clone())),
+            Protocol::Gt06 => Box::new(gt06::Gt06Protocol::default()),
+            Protocol::Jt808 => Box::new(jt808::Jt808Protocol::default()),
+            Protocol::Teltonika => Box::new(teltonika::TeltonikaProtocol),
+            Protocol::Auto => return None,
+        };
+        Some(protocol)
+    }
+
+    /// Returns `None` while more bytes are needed to tell the protocols apart.
+    pub fn detect(data: &[u8]) -> Result<Option<Protocol>> {
+        let protocol = match data {
+            [] | [0x78 | 0x79 | 0x00] => return Ok(None),
+            // JSON login, text devices have to log in before sending NMEA sentences
+            [b'{', ..] => Protocol::Text,
+            [0x78, 0x78, ..] | [0x79, 0x79, ..] => Protocol::Gt06,
+            [0x7E, ..] => Protocol::Jt808,
+            // Length of the 15 digit IMEI
+            [0x00, 0x0F, ..] => Protocol::Teltonika,
+            _ => bail!(
+                "unknown protocol starting with {}",
+                hex(&data[..data.len().min(4)])
+            ),
+        };
+        Ok(Some(protocol))
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Protocol::Text => "text",
+            Protocol::Gt06 => "gt06",
+            Protocol::Jt808 => "jt808",
+            Protocol::Teltonika => "teltonika",
+            Protocol::Auto => "auto",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub enum DeviceMessage {
+    /// Asks for the code to log in with, checked against nothing
+    Register(ClientInfo),
+    Login(ClientInfo),
+    Heartbeat,
+    Report {
+        text: String,
+        positions: Vec<Position>,
+    },
+}
+
+/// Answer to the oldest command written to the device that is still unanswered.
+pub enum CommandReply {
+    Acknowledged,
+    Failed(String),
+    Replied(String),
+}
+
+/// Outcome of a [`DeviceMessage::Register`].
+pub enum Registration {
+    /// Carries the code the device logs in with
+    Accepted(String),
+    /// The device logs in with the token configured for it, which is never sent
+    AlreadyRegistered,
+    Refused,
+}
+
+pub struct Decoded {
+    pub message: DeviceMessage,
+    /// Bytes the device expects back once the message is handled
+    pub ack: Option<Vec<u8>>,
+    pub reply: Option<CommandReply>,
+    /// Signal quality reported with the message
+    pub csq: Option<i32>,
+}
+
+impl Decoded {
+    pub fn new(message: DeviceMessage) -> Self {
+        Self {
+            message,
+            ack: None,
+            reply: None,
+            csq: None,
+        }
+    }
+
+    pub fn with_ack(message: DeviceMessage, ack: Vec<u8>) -> Self {
+        Self {
+            message,
+            ack: Some(ack),
+            reply: None,
+            csq: None,
+        }
+    }
+
+    pub fn with_reply(mut self, reply: CommandReply) -> Self {
+        self.reply.replace(reply);
+        self
+    }
+
+    pub fn with_csq(mut self, csq: Option<i32>) -> Self {
+        self.csq = csq;
+        self
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Parses the hex dumps the test frames are written as.
+#[cfg(test)]
+pub(crate) fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
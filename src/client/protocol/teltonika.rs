@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow, bail};
+use chrono::DateTime;
+use serde_json::Value;
+
+use super::{CommandReply, Decoded, DeviceMessage, DeviceProtocol, Protocol, hex};
+use crate::client::command::ClientCommand;
+use crate::client::framing::Framing;
+use crate::client::info::ClientInfo;
+use crate::client::position::Position;
+
+const CODEC_8: u8 = 0x08;
+const CODEC_8_EXTENDED: u8 = 0x8E;
+const CODEC_12: u8 = 0x0C;
+
+const COMMAND: u8 = 0x05;
+const RESPONSE: u8 = 0x06;
+
+const IMEI_ACCEPTED: u8 = 0x01;
+
+pub struct TeltonikaProtocol;
+
+impl DeviceProtocol for TeltonikaProtocol {
+    fn protocol(&self) -> Protocol {
+        Protocol::Teltonika
+    }
+
+    fn framing(&self) -> Framing {
+        Framing::Custom(frame_length)
+    }
+
+    fn decode(&mut self, frame: &[u8]) -> Result<Decoded> {
+        decode(frame)
+    }
+
+    fn encode_command(&mut self, command: &ClientCommand) -> Result<Vec<u8>> {
+        Ok(encode_command(&command.payload()?))
+    }
+}
+
+/// Total length of the IMEI handshake or AVL packet at the start of `buf`.
+fn frame_length(buf: &[u8]) -> Result<Option<usize>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    // The IMEI handshake starts with its length, AVL packets with a zero preamble
+    let imei_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if imei_len > 0 {
+        return Ok(Some(2 + imei_len));
+    }
+    if buf.len() < 8 {
+        return Ok(None);
+    }
+    if buf[2..4] != [0, 0] {
+        bail!("invalid Teltonika preamble: {}", hex(&buf[..4]));
+    }
+
+    let data_len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+    // preamble, data length, data and CRC
+    Ok(Some(4 + 4 + data_len + 4))
+}
+
+fn decode(frame: &[u8]) -> Result<Decoded> {
+    if frame[..2] != [0, 0] {
+        let imei = String::from_utf8_lossy(&frame[2..]).to_string();
+        let info = ClientInfo {
+            imei,
+            iccid: String::new(),
+            fver: String::new(),
+            csq: None,
+            token: None,
+        };
+        return Ok(Decoded::with_ack(
+            DeviceMessage::Login(info),
+            vec![IMEI_ACCEPTED],
+        ));
+    }
+
+    let data = &frame[8..frame.len() - 4];
+    let crc = &frame[frame.len() - 4..];
+    let expected = u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]);
+    let actual = crc16_ibm(data) as u32;
+    if expected != actual {
+        bail!(
+            "Teltonika CRC mismatch: expected {:04X}, got {:04X}",
+            expected,
+            actual
+        );
+    }
+
+    let mut reader = Reader::new(data);
+    let codec = reader.u8()?;
+    let count = reader.u8()?;
+    match codec {
+        CODEC_8 | CODEC_8_EXTENDED => {
+            let mut positions = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let start = reader.offset;
+                let mut position = parse_record(&mut reader, codec == CODEC_8_EXTENDED)?;
+                position.raw = hex(&data[start..reader.offset]);
+                positions.push(position);
+            }
+            if reader.u8()? != count {
+                bail!("Teltonika record counts do not match");
+            }
+
+            let text = format!("AVL codec={:02X} records={}", codec, count);
+            let message = DeviceMessage::Report { text, positions };
+            Ok(Decoded::with_ack(
+                message,
+                (count as u32).to_be_bytes().to_vec(),
+            ))
+        }
+        CODEC_12 => {
+            let kind = reader.u8()?;
+            let size = reader.u32()? as usize;
+            let content = reader.take(size)?;
+            if kind != RESPONSE {
+                bail!("unexpected Codec 12 message type: {:02X}", kind);
+            }
+
+            let text = String::from_utf8_lossy(content).to_string();
+            let message = DeviceMessage::Report {
+                text: text.clone(),
+                positions: Vec::new(),
+            };
+            Ok(Decoded::new(message).with_reply(CommandReply::Replied(text)))
+        }
+        codec => bail!("unsupported Teltonika codec: {:02X}", codec),
+    }
+}
+
+/// Encodes a command as a Codec 12 packet.
+fn encode_command(command: &[u8]) -> Vec<u8> {
+    let mut data = vec![CODEC_12, 1, COMMAND];
+    data.extend_from_slice(&(command.len() as u32).to_be_bytes());
+    data.extend_from_slice(command);
+    data.push(1);
+
+    let mut packet = vec![0; 4];
+    packet.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    packet.extend_from_slice(&data);
+    packet.extend_from_slice(&(crc16_ibm(&data) as u32).to_be_bytes());
+    packet
+}
+
+// timestamp (8), priority (1), GPS element (15), IO element
+fn parse_record(reader: &mut Reader, extended: bool) -> Result<Position> {
+    let timestamp = reader.u64()? as i64;
+    let _priority = reader.u8()?;
+
+    let longitude = reader.u32()? as i32 as f64 / 10_000_000.0;
+    let latitude = reader.u32()? as i32 as f64 / 10_000_000.0;
+    let altitude = reader.u16()? as i16;
+    let angle = reader.u16()?;
+    let satellites = reader.u8()?;
+    let speed = reader.u16()?;
+
+    let mut position = Position::new("TELTONIKA", String::new());
+    position.time = DateTime::from_timestamp_millis(timestamp);
+    // Records without a fix carry zeroed coordinates
+    position.valid = satellites > 0 && (latitude != 0.0 || longitude != 0.0);
+    position.latitude = Some(latitude);
+    position.longitude = Some(longitude);
+    position.altitude = Some(altitude as f64);
+    position.course = Some(angle as f64);
+    position.satellites = Some(satellites);
+    position.speed = Some(speed as f64);
+    position.io = parse_io(reader, extended)?;
+    Ok(position)
+}
+
+// Codec 8 uses 1 byte ids and counts, Codec 8 Extended 2 bytes and adds variable length values
+fn parse_io(reader: &mut Reader, extended: bool) -> Result<BTreeMap<u16, Value>> {
+    let read_id = |reader: &mut Reader| -> Result<u16> {
+        if extended {
+            reader.u16()
+        } else {
+            reader.u8().map(u16::from)
+        }
+    };
+
+    let _event_id = read_id(reader)?;
+    let _total = read_id(reader)?;
+
+    let mut io = BTreeMap::new();
+    for width in [1, 2, 4, 8] {
+        let count = read_id(reader)?;
+        for _ in 0..count {
+            let id = read_id(reader)?;
+            let value = reader
+                .take(width)?
+                .iter()
+                .fold(0u64, |value, &b| (value << 8) | b as u64);
+            io.insert(id, Value::from(value));
+        }
+    }
+
+    if extended {
+        let count = reader.u16()?;
+        for _ in 0..count {
+            let id = reader.u16()?;
+            let len = reader.u16()? as usize;
+            io.insert(id, Value::from(hex(reader.take(len)?)));
+        }
+    }
+    Ok(io)
+}
+
+// CRC-16/ARC, called CRC-16/IBM in the Teltonika documentation
+fn crc16_ibm(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or(anyhow!("Teltonika packet too short"))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::protocol::unhex;
+
+    // Examples of the Teltonika wiki
+    const IMEI_HANDSHAKE: &str = "000F333536333037303432343431303133";
+    const CODEC_8_PACKET: &str = "000000000000003608010000016B40D8EA30010000000000000000000000000000000105021503010101425E0F01F10000601A014E0000000000000000010000C7CF";
+    const CODEC_8E_PACKET: &str = "000000000000004A8E010000016B412CEE000100000000000000000000000000000000010005000100010100010011001D00010010015E2C880002000B000000003544C87A000E000000001DD7E06A00000100002994";
+    const GETINFO_COMMAND: &str = "000000000000000F0C010500000007676574696E666F0100004312";
+    const GETINFO_RESPONSE: &str = "00000000000000900C010600000088494E493A323031392F372F323220373A3232205254433A323031392F372F323220373A3533205253543A32204552523A312053523A302042523A302043463A302046473A3020464C3A302054553A302F302055543A3020534D533A30204E4F4750533A303A3330204750533A31205341543A302052533A332052463A36352053463A31204D443A30010000C78F";
+
+    fn positions(decoded: Decoded) -> Vec<Position> {
+        match decoded.message {
+            DeviceMessage::Report { positions, .. } => positions,
+            _ => panic!("expected a report"),
+        }
+    }
+
+    #[test]
+    fn crc16_ibm_matches_check_value() {
+        assert_eq!(crc16_ibm(b"123456789"), 0xBB3D);
+    }
+
+    #[test]
+    fn frame_length_of_handshake_and_avl_packets() {
+        assert_eq!(frame_length(&unhex(IMEI_HANDSHAKE)).unwrap(), Some(17));
+        let packet = unhex(CODEC_8_PACKET);
+        assert_eq!(frame_length(&packet[..1]).unwrap(), None);
+        assert_eq!(frame_length(&packet[..7]).unwrap(), None);
+        assert_eq!(frame_length(&packet[..8]).unwrap(), Some(packet.len()));
+        assert!(frame_length(&unhex("0000000100000036")).is_err());
+    }
+
+    #[test]
+    fn decodes_imei_handshake() {
+        let decoded = decode(&unhex(IMEI_HANDSHAKE)).unwrap();
+        assert_eq!(decoded.ack, Some(vec![IMEI_ACCEPTED]));
+        let DeviceMessage::Login(info) = decoded.message else {
+            panic!("expected a login");
+        };
+        assert_eq!(info.imei, "356307042441013");
+    }
+
+    #[test]
+    fn decodes_codec_8() {
+        let decoded = decode(&unhex(CODEC_8_PACKET)).unwrap();
+        assert_eq!(decoded.ack, Some(vec![0, 0, 0, 1]));
+
+        let positions = positions(decoded);
+        assert_eq!(positions.len(), 1);
+        let position = &positions[0];
+        assert_eq!(
+            position.time,
+            DateTime::from_timestamp_millis(0x016B40D8EA30)
+        );
+        assert!(!position.valid);
+        let io: Vec<(u16, u64)> = position
+            .io
+            .iter()
+            .map(|(id, value)| (*id, value.as_u64().unwrap()))
+            .collect();
+        assert_eq!(io, [(1, 1), (21, 3), (66, 0x5E0F), (78, 0), (241, 0x601A)]);
+    }
+
+    #[test]
+    fn decodes_codec_8_extended() {
+        let decoded = decode(&unhex(CODEC_8E_PACKET)).unwrap();
+        assert_eq!(decoded.ack, Some(vec![0, 0, 0, 1]));
+
+        let positions = positions(decoded);
+        assert_eq!(positions.len(), 1);
+        let io: Vec<(u16, u64)> = positions[0]
+            .io
+            .iter()
+            .map(|(id, value)| (*id, value.as_u64().unwrap()))
+            .collect();
+        assert_eq!(
+            io,
+            [
+                (1, 1),
+                (11, 0x3544C87A),
+                (14, 0x1DD7E06A),
+                (16, 0x015E2C88),
+                (17, 0x1D)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_crc_mismatch() {
+        let mut packet = unhex(CODEC_8_PACKET);
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        assert!(decode(&packet).is_err());
+    }
+
+    #[test]
+    fn encodes_codec_12_command() {
+        assert_eq!(encode_command(b"getinfo"), unhex(GETINFO_COMMAND));
+    }
+
+    #[test]
+    fn decodes_codec_12_response() {
+        let decoded = decode(&unhex(GETINFO_RESPONSE)).unwrap();
+        assert!(decoded.ack.is_none());
+        let Some(CommandReply::Replied(text)) = decoded.reply else {
+            panic!("expected a reply");
+        };
+        assert!(text.starts_with("INI:2019/7/22 7:22 RTC:2019/7/22 7:53"));
+        assert!(text.ends_with("MD:0"));
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{Result, anyhow};
+use log::warn;
+
+use super::{CommandReply, Decoded, DeviceMessage, DeviceProtocol, Protocol};
+use crate::client::at;
+use crate::client::command::{ClientCommand, PayloadEncoding};
+use crate::client::framing::Framing;
+use crate::client::info::ClientInfo;
+use crate::client::nmea;
+
+const HEARTBEAT: &str = "HEARTBEAT";
+
+/// A JSON encoded [`ClientInfo`] login, then one text message per frame.
+pub struct TextProtocol {
+    framing: Framing,
+    logged_in: bool,
+    /// Name of the last text command written, replies echo it or start with it
+    last_command: Option<String>,
+}
+
+impl TextProtocol {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            logged_in: false,
+            last_command: None,
+        }
+    }
+}
+
+impl DeviceProtocol for TextProtocol {
+    fn protocol(&self) -> Protocol {
+        Protocol::Text
+    }
+
+    fn framing(&self) -> Framing {
+        self.framing.clone()
+    }
+
+    fn decode(&mut self, frame: &[u8]) -> Result<Decoded> {
+        let received = String::from_utf8_lossy(frame).to_string();
+        // `HEARTBEAT,<csq>` reports the signal quality along with the heartbeat
+        if let Some(rest) = received.strip_prefix(HEARTBEAT)
+            && (rest.is_empty() || rest.starts_with(','))
+        {
+            let csq = rest
+                .strip_prefix(',')
+                .and_then(|csq| csq.trim().parse().ok());
+            return Ok(Decoded::new(DeviceMessage::Heartbeat).with_csq(csq));
+        }
+
+        if !self.logged_in {
+            let info = ClientInfo::from_json(&received)
+                .ok_or(anyhow!("invalid client info: {}", received))?;
+            self.logged_in = true;
+            return Ok(Decoded::new(DeviceMessage::Login(info)));
+        }
+
+        if nmea::is_sentence(&received) {
+            let mut positions = Vec::new();
+            match nmea::parse(&received) {
+                Ok(position) => positions.push(position),
+                Err(e) => warn!(target: "text_protocol", "invalid NMEA sentence: {}", e),
+            }
+            return Ok(Decoded::new(DeviceMessage::Report {
+                text: received,
+                positions,
+            }));
+        }
+
+        let csq = at::csq(&received);
+        let decoded = Decoded::new(DeviceMessage::Report {
+            text: received.clone(),
+            positions: Vec::new(),
+        })
+        .with_csq(csq);
+        // Other lines are reports, unless they look like the answer to a command
+        if self.is_reply(&received) {
+            return Ok(decoded.with_reply(CommandReply::Replied(received)));
+        }
+        Ok(decoded)
+    }
+
+    /// Firmware predating framing sends its messages without a trailing `\n`. Only the login
+    /// and heartbeats are recognized with `newline`, those that cannot be continued.
+    fn is_unterminated_frame(&self, buffered: &[u8]) -> bool {
+        match self.framing {
+            Framing::NewlineOrRead => return true,
+            Framing::Newline => {}
+            _ => return false,
+        }
+
+        let received = String::from_utf8_lossy(buffered);
+        if let Some(rest) = received.strip_prefix(HEARTBEAT) {
+            // A single digit signal quality may be the start of a longer one
+            return rest.is_empty()
+                || rest
+                    .strip_prefix(',')
+                    .is_some_and(|csq| csq.len() == 2 && csq.parse::<u8>().is_ok());
+        }
+        !self.logged_in
+            && received.starts_with('{')
+            && serde_json::from_str::<serde_json::Value>(&received).is_ok()
+    }
+
+    fn encode_command(&mut self, command: &ClientCommand) -> Result<Vec<u8>> {
+        let mut data = command.payload()?;
+        self.last_command = (command.encoding == PayloadEncoding::Text)
+            .then(|| command_name(&command.command).to_string())
+            .filter(|name| !name.is_empty());
+        data.extend_from_slice(command.terminator().as_bytes());
+        Ok(data)
+    }
+}
+
+impl TextProtocol {
+    /// AT information and result lines, or lines echoing the last command or prefixed with its name.
+    fn is_reply(&self, line: &str) -> bool {
+        let line = line.trim();
+        at::is_final(line)
+            || line.starts_with('+')
+            || self
+                .last_command
+                .as_ref()
+                .is_some_and(|name| line.starts_with(name.as_str()))
+    }
+}
+
+/// `CFG` of `CFG=1` or `CFG,1`, the whole command when it has no parameters.
+fn command_name(command: &str) -> &str {
+    let command = command.trim();
+    let end = command
+        .find(['=', ',', '?', ':', ' '])
+        .unwrap_or(command.len());
+    &command[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newline_accepts_only_complete_unterminated_messages() {
+        let mut protocol = TextProtocol::new(Framing::Newline);
+        let login = br#"{"imei":"861001","iccid":"1","fver":"FW1"}"#;
+        assert!(!protocol.is_unterminated_frame(br#"{"imei":"861001""#));
+        assert!(protocol.is_unterminated_frame(login));
+        assert!(protocol.is_unterminated_frame(b"HEARTBEAT"));
+        assert!(protocol.is_unterminated_frame(b"HEARTBEAT,23"));
+        assert!(!protocol.is_unterminated_frame(b"HEARTBEAT,2"));
+        assert!(!protocol.is_unterminated_frame(b"HEARTBEAT,"));
+
+        protocol.decode(login).unwrap();
+        assert!(!protocol.is_unterminated_frame(login));
+        assert!(!protocol.is_unterminated_frame(b"REPORT,1"));
+    }
+
+    #[test]
+    fn newline_or_read_accepts_any_unterminated_message() {
+        let protocol = TextProtocol::new(Framing::NewlineOrRead);
+        assert!(protocol.is_unterminated_frame(b"REPORT,1"));
+        assert!(protocol.is_unterminated_frame(b"HEARTBEAT,2"));
+    }
+
+    #[test]
+    fn only_replies_are_matched_to_commands() {
+        let mut protocol = TextProtocol::new(Framing::Newline);
+        protocol
+            .decode(br#"{"imei":"861001","iccid":"1","fver":"FW1"}"#)
+            .unwrap();
+        assert!(protocol.decode(b"STATUS,12").unwrap().reply.is_none());
+
+        let command = ClientCommand::new(vec!["861001".to_string()], "CFG=1".to_string());
+        assert_eq!(protocol.encode_command(&command).unwrap(), b"CFG=1\n");
+        assert!(protocol.decode(b"STATUS,12").unwrap().reply.is_none());
+        assert!(protocol.decode(b"CFG:OK").unwrap().reply.is_some());
+        assert!(protocol.decode(b"+CSQ: 20,0").unwrap().reply.is_some());
+        assert!(protocol.decode(b"ERROR").unwrap().reply.is_some());
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt::Display;
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+use super::info::RegisteredClientInfo;
+
+/// Registered devices a command is sent to, resolved when it is dispatched.
+/// Every criterion that is set must match.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceSelector {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags_any: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags_all: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags_not: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// A trailing `*` matches any suffix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fver: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imei_prefix: Option<String>,
+}
+
+impl DeviceSelector {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Matches the device, `fver` is the version of its current session if it is online.
+    pub fn matches(&self, info: &RegisteredClientInfo, fver: &str) -> bool {
+        let has_tag = |tag: &String| info.tags.contains(tag);
+
+        (self.tags_any.is_empty() || self.tags_any.iter().any(has_tag))
+            && self.tags_all.iter().all(has_tag)
+            && !self.tags_not.iter().any(has_tag)
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| info.name.as_ref() == Some(name))
+            && self
+                .fver
+                .as_ref()
+                .is_none_or(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => fver.starts_with(prefix),
+                    None => fver == pattern,
+                })
+            && self
+                .imei_prefix
+                .as_ref()
+                .is_none_or(|prefix| info.base_info.imei.starts_with(prefix))
+    }
+
+    /// Adds a console target item, returns false for plain IMEIs.
+    ///
+    /// Items are `tag=a` (any), `+tag=a` (all), `!tag=a` (not), `name=x`, `fver=x` and `861234*`.
+    pub fn parse_item(&mut self, item: &str) -> Result<bool> {
+        if let Some(prefix) = item.strip_suffix('*')
+            && !item.contains('=')
+        {
+            self.imei_prefix = Some(prefix.to_string());
+            return Ok(true);
+        }
+
+        let Some((key, value)) = item.split_once('=') else {
+            return Ok(false);
+        };
+        let value = value.trim().to_string();
+        match key.trim() {
+            "tag" => self.tags_any.push(value),
+            "+tag" => self.tags_all.push(value),
+            "!tag" => self.tags_not.push(value),
+            "name" => self.name = Some(value),
+            "fver" => self.fver = Some(value),
+            key => bail!("unknown selector {}", key),
+        }
+        Ok(true)
+    }
+}
+
+impl Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut items = Vec::new();
+        items.extend(self.tags_any.iter().map(|tag| format!("tag={}", tag)));
+        items.extend(self.tags_all.iter().map(|tag| format!("+tag={}", tag)));
+        items.extend(self.tags_not.iter().map(|tag| format!("!tag={}", tag)));
+        items.extend(self.name.iter().map(|name| format!("name={}", name)));
+        items.extend(self.fver.iter().map(|fver| format!("fver={}", fver)));
+        items.extend(self.imei_prefix.iter().map(|prefix| format!("{}*", prefix)));
+        write!(f, "{}", items.join(","))
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{RwLock, mpsc};
+use uuid::Uuid;
+
+use super::command::ClientCommand;
+use super::info::ClientInfo;
+use super::protocol::Protocol;
+use crate::settings::DuplicateLoginPolicy;
+
+/// Commands a single session can have waiting, further ones fail until it catches up
+pub const COMMAND_QUEUE_SIZE: usize = 64;
+
+pub enum SessionControl {
+    Kick(String),
+    /// The server is shutting down, commands already queued are sent first
+    Close,
+}
+
+#[derive(Default)]
+pub struct SessionCounters {
+    pub bytes_received: AtomicU64,
+    pub frames_received: AtomicU64,
+    pub commands_sent: AtomicU64,
+}
+
+impl SessionCounters {
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub id: Uuid,
+    pub info: ClientInfo,
+    pub peer: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub protocol: Protocol,
+    pub counters: Arc<SessionCounters>,
+    pub commands: mpsc::Sender<ClientCommand>,
+    pub control: mpsc::Sender<SessionControl>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SessionSnapshot {
+    pub id: Uuid,
+    pub imei: String,
+    pub peer: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub protocol: Protocol,
+    pub bytes_received: u64,
+    pub frames_received: u64,
+    pub commands_sent: u64,
+}
+
+impl From<&SessionHandle> for SessionSnapshot {
+    fn from(handle: &SessionHandle) -> Self {
+        let counters = &handle.counters;
+        Self {
+            id: handle.id,
+            imei: handle.info.identifier(),
+            peer: handle.peer,
+            connected_at: handle.connected_at,
+            protocol: handle.protocol,
+            bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+            frames_received: counters.frames_received.load(Ordering::Relaxed),
+            commands_sent: counters.commands_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sessions of the connected devices by IMEI, oldest first.
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: RwLock<HashMap<String, Vec<SessionHandle>>>,
+}
+
+impl SessionRegistry {
+    /// Adds the session according to `policy`, kicking or rejecting duplicates.
+    pub async fn insert(&self, handle: SessionHandle, policy: DuplicateLoginPolicy) -> Result<()> {
+        let id = handle.info.identifier();
+        let mut sessions = self.sessions.write().await;
+        let existing = sessions.entry(id.clone()).or_default();
+        // A device logging in again on the same connection keeps its session
+        existing.retain(|s| s.id != handle.id);
+
+        if !existing.is_empty() {
+            match policy {
+                DuplicateLoginPolicy::KickOld => {
+                    for old in existing.drain(..) {
+                        let reason = format!("{} logged in again from {}", id, handle.peer);
+                        old.control.try_send(SessionControl::Kick(reason)).ok();
+                    }
+                }
+                DuplicateLoginPolicy::RejectNew => bail!("{} is already connected", id),
+                DuplicateLoginPolicy::AllowBoth => {}
+            }
+        }
+
+        existing.push(handle);
+        Ok(())
+    }
+
+    pub async fn remove(&self, imei: &str, session_id: Uuid) {
+        let mut sessions = self.sessions.write().await;
+        if let Some(existing) = sessions.get_mut(imei) {
+            existing.retain(|s| s.id != session_id);
+            if existing.is_empty() {
+                sessions.remove(imei);
+            }
+        }
+    }
+
+    pub async fn set_csq(&self, imei: &str, session_id: Uuid, csq: i32) {
+        let mut sessions = self.sessions.write().await;
+        let session = sessions
+            .get_mut(imei)
+            .and_then(|existing| existing.iter_mut().find(|s| s.id == session_id));
+        if let Some(session) = session {
+            session.info.csq = Some(csq);
+        }
+    }
+
+    /// Newest session of the device.
+    pub async fn get(&self, imei: &str) -> Option<SessionHandle> {
+        let sessions = self.sessions.read().await;
+        sessions.get(imei).and_then(|s| s.last()).cloned()
+    }
+
+    pub async fn ids(&self) -> Vec<String> {
+        self.sessions.read().await.keys().cloned().collect()
+    }
+
+    pub async fn snapshots(&self) -> Vec<SessionSnapshot> {
+        let sessions = self.sessions.read().await;
+        sessions
+            .values()
+            .flatten()
+            .map(SessionSnapshot::from)
+            .collect()
+    }
+
+    pub async fn infos(&self) -> Vec<ClientInfo> {
+        let sessions = self.sessions.read().await;
+        sessions
+            .values()
+            .filter_map(|s| s.last())
+            .map(|s| s.info.clone())
+            .collect()
+    }
+
+    pub async fn close_all(&self) {
+        let sessions = self.sessions.read().await;
+        for session in sessions.values().flatten() {
+            session.control.try_send(SessionControl::Close).ok();
+        }
+    }
+
+    pub async fn kick(&self, imei: &str, reason: &str) -> bool {
+        let sessions = self.sessions.read().await;
+        let Some(existing) = sessions.get(imei) else {
+            return false;
+        };
+
+        for session in existing {
+            session
+                .control
+                .try_send(SessionControl::Kick(reason.to_string()))
+                .ok();
+        }
+        true
+    }
+}
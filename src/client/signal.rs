@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Signal quality reported by a device, one JSON line in `output_dir/csq/<imei>`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CsqSample {
+    pub time: DateTime<Utc>,
+    /// 0 to 31, as answered to `AT+CSQ`
+    pub csq: i32,
+}
+
+impl CsqSample {
+    /// None for values outside of 0 to 31, such as 99 when the modem does not know.
+    pub fn new(csq: i32) -> Option<Self> {
+        (0..=31).contains(&csq).then(|| Self {
+            time: Utc::now(),
+            csq,
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsPeriod {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl StatsPeriod {
+    fn seconds(&self) -> i64 {
+        match self {
+            StatsPeriod::Hour => 3600,
+            StatsPeriod::Day => 86400,
+        }
+    }
+
+    /// Start of the UTC hour or day `time` falls in.
+    fn start_of(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let timestamp = time.timestamp();
+        let start = timestamp - timestamp.rem_euclid(self.seconds());
+        DateTime::from_timestamp(start, 0).unwrap_or(time)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CsqStats {
+    pub start: DateTime<Utc>,
+    pub count: usize,
+    pub min: i32,
+    pub avg: f64,
+    pub max: i32,
+}
+
+/// Min, average and max of the samples for each period that has any, oldest first.
+pub fn stats(samples: &[CsqSample], period: StatsPeriod) -> Vec<CsqStats> {
+    let mut buckets: BTreeMap<DateTime<Utc>, Vec<i32>> = BTreeMap::new();
+    for sample in samples {
+        let start = period.start_of(sample.time);
+        buckets.entry(start).or_default().push(sample.csq);
+    }
+
+    buckets
+        .into_iter()
+        .map(|(start, values)| CsqStats {
+            start,
+            count: values.len(),
+            min: values.iter().copied().min().unwrap_or_default(),
+            avg: values.iter().sum::<i32>() as f64 / values.len() as f64,
+            max: values.iter().copied().max().unwrap_or_default(),
+        })
+        .collect()
+}
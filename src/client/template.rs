@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Commands accepted by devices running a firmware version.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CommandProfile {
+    /// Whether commands not rendered from a template may be sent
+    #[serde(default = "default_allow_raw")]
+    pub allow_raw: bool,
+    #[serde(default)]
+    pub templates: BTreeMap<String, CommandTemplate>,
+    /// Default terminator of text commands, such as `\r\n`
+    #[serde(default)]
+    pub terminator: Option<String>,
+    /// Default charset of text commands, such as `gbk`
+    #[serde(default)]
+    pub charset: Option<String>,
+}
+
+fn default_allow_raw() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CommandTemplate {
+    /// Command text, `{name}` is replaced with the parameter's value
+    pub format: String,
+    #[serde(default)]
+    pub params: BTreeMap<String, ParamSpec>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParamSpec {
+    Integer {
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+    String {
+        max_len: Option<usize>,
+        /// Only these values are accepted when not empty
+        #[serde(default)]
+        choices: Vec<String>,
+    },
+    Bool {
+        #[serde(default = "default_true_text")]
+        true_text: String,
+        #[serde(default = "default_false_text")]
+        false_text: String,
+    },
+}
+
+fn default_true_text() -> String {
+    "1".to_string()
+}
+
+fn default_false_text() -> String {
+    "0".to_string()
+}
+
+/// Template to render for each target instead of a raw command.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TemplateCall {
+    pub name: String,
+    #[serde(default)]
+    pub params: BTreeMap<String, Value>,
+}
+
+impl Display for TemplateCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        write!(f, "{}({})", self.name, params.join(", "))
+    }
+}
+
+impl CommandProfile {
+    pub fn render(&self, call: &TemplateCall) -> Result<String> {
+        let template = self
+            .templates
+            .get(&call.name)
+            .ok_or(anyhow!("unknown template {}", call.name))?;
+        template.render(&call.params)
+    }
+}
+
+impl CommandTemplate {
+    pub fn render(&self, params: &BTreeMap<String, Value>) -> Result<String> {
+        if let Some(name) = params.keys().find(|name| !self.params.contains_key(*name)) {
+            bail!("unknown parameter {}", name);
+        }
+
+        let mut values = BTreeMap::new();
+        for (name, spec) in &self.params {
+            let value = params
+                .get(name)
+                .ok_or(anyhow!("missing parameter {}", name))?;
+            let value = spec
+                .render(value)
+                .map_err(|e| anyhow!("invalid parameter {}: {}", name, e))?;
+            values.insert(name.as_str(), value);
+        }
+
+        // Placeholders are replaced in one pass, values are never expanded again
+        let mut command = String::new();
+        let mut rest = self.format.as_str();
+        while let Some(start) = rest.find('{') {
+            command.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let value = rest
+                .find('}')
+                .and_then(|end| values.get(&rest[1..end]).map(|value| (end, value)));
+            match value {
+                Some((end, value)) => {
+                    command.push_str(value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    command.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        command.push_str(rest);
+        Ok(command)
+    }
+}
+
+impl ParamSpec {
+    fn render(&self, value: &Value) -> Result<String> {
+        match self {
+            ParamSpec::Integer { min, max } => {
+                let value = value.as_i64().ok_or(anyhow!("expected an integer"))?;
+                if min.is_some_and(|min| value < min) || max.is_some_and(|max| value > max) {
+                    bail!("{} is out of range", value);
+                }
+                Ok(value.to_string())
+            }
+            ParamSpec::String { max_len, choices } => {
+                let value = value.as_str().ok_or(anyhow!("expected a string"))?;
+                // Line breaks would let one parameter smuggle in another command
+                if value.chars().any(char::is_control) {
+                    bail!("control characters are not allowed");
+                }
+                if max_len.is_some_and(|max_len| value.chars().count() > max_len) {
+                    bail!("longer than {} characters", max_len.unwrap_or_default());
+                }
+                if !choices.is_empty() && !choices.iter().any(|choice| choice == value) {
+                    bail!("expected one of {}", choices.join(", "));
+                }
+                Ok(value.to_string())
+            }
+            ParamSpec::Bool {
+                true_text,
+                false_text,
+            } => match value.as_bool() {
+                Some(true) => Ok(true_text.clone()),
+                Some(false) => Ok(false_text.clone()),
+                None => bail!("expected a boolean"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn template() -> CommandTemplate {
+        serde_json::from_value(json!({
+            "format": "CFG,{interval},{name},{on}",
+            "params": {
+                "interval": {"type": "integer", "min": 10, "max": 3600},
+                "name": {"type": "string", "max_len": 8},
+                "on": {"type": "bool", "true_text": "ON", "false_text": "OFF"}
+            }
+        }))
+        .unwrap()
+    }
+
+    fn params(value: Value) -> BTreeMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        let command = template()
+            .render(&params(json!({"interval": 60, "name": "bus1", "on": true})))
+            .unwrap();
+        assert_eq!(command, "CFG,60,bus1,ON");
+    }
+
+    #[test]
+    fn refuses_missing_unknown_and_invalid_parameters() {
+        let template = template();
+        let missing = template.render(&params(json!({"interval": 60, "name": "bus1"})));
+        assert_eq!(missing.unwrap_err().to_string(), "missing parameter on");
+
+        let unknown = template.render(&params(
+            json!({"interval": 60, "name": "bus1", "on": true, "apn": "x"}),
+        ));
+        assert_eq!(unknown.unwrap_err().to_string(), "unknown parameter apn");
+
+        let out_of_range =
+            template.render(&params(json!({"interval": 5, "name": "bus1", "on": true})));
+        assert!(out_of_range.is_err());
+        let wrong_type = template.render(&params(
+            json!({"interval": "60", "name": "bus1", "on": true}),
+        ));
+        assert!(wrong_type.is_err());
+    }
+
+    #[test]
+    fn never_expands_values_or_accepts_control_characters() {
+        let template = template();
+        let command = template
+            .render(&params(
+                json!({"interval": 60, "name": "{on}", "on": false}),
+            ))
+            .unwrap();
+        assert_eq!(command, "CFG,60,{on},OFF");
+
+        let injected = template.render(&params(
+            json!({"interval": 60, "name": "a\nRESET", "on": true}),
+        ));
+        assert!(injected.is_err());
+
+        let literal: CommandTemplate =
+            serde_json::from_value(json!({"format": "SET{x}{", "params": {}})).unwrap();
+        assert_eq!(literal.render(&BTreeMap::new()).unwrap(), "SET{x}{");
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{Notify, RwLock};
+use uuid::Uuid;
+
+use super::command::ClientCommand;
+
+/// How long finished commands are kept for polling
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Queued,
+    Written,
+    Acknowledged,
+    Replied,
+    Failed,
+    TimedOut,
+}
+
+impl DeliveryStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, DeliveryStatus::Queued | DeliveryStatus::Written)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Delivery {
+    pub status: DeliveryStatus,
+    pub reply: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CommandRecord {
+    pub id: Uuid,
+    pub command: String,
+    pub created_at: DateTime<Utc>,
+    /// Delivery to each device, broadcasts gain entries as devices pick them up
+    pub targets: BTreeMap<String, Delivery>,
+}
+
+impl CommandRecord {
+    pub fn is_finished(&self) -> bool {
+        !self.targets.is_empty() && self.targets.values().all(|d| d.status.is_finished())
+    }
+}
+
+/// Delivery status of every command sent, by command ID.
+pub struct CommandTracker {
+    timeout: Duration,
+    records: RwLock<HashMap<Uuid, CommandRecord>>,
+    updated: Notify,
+}
+
+impl CommandTracker {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            records: RwLock::new(HashMap::new()),
+            updated: Notify::new(),
+        }
+    }
+
+    /// How long a device has to answer a command once it is written.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub async fn track(&self, command: &ClientCommand) {
+        let now = Utc::now();
+        let targets = command
+            .targets()
+            .iter()
+            .map(|target| {
+                let delivery = Delivery {
+                    status: DeliveryStatus::Queued,
+                    reply: None,
+                    updated_at: now,
+                };
+                (target.clone(), delivery)
+            })
+            .collect();
+
+        let mut records = self.records.write().await;
+        records.retain(|_, record| !expired(record.created_at, RETENTION));
+        records.insert(
+            command.id,
+            CommandRecord {
+                id: command.id,
+                command: command.summary(),
+                created_at: now,
+                targets,
+            },
+        );
+    }
+
+    pub async fn update(
+        &self,
+        id: Uuid,
+        target: &str,
+        status: DeliveryStatus,
+        reply: Option<String>,
+    ) {
+        let mut records = self.records.write().await;
+        let Some(record) = records.get_mut(&id) else {
+            return;
+        };
+
+        record.targets.insert(
+            target.to_string(),
+            Delivery {
+                status,
+                reply,
+                updated_at: Utc::now(),
+            },
+        );
+        self.updated.notify_waiters();
+    }
+
+    pub async fn get(&self, id: Uuid) -> Option<CommandRecord> {
+        let mut records = self.records.write().await;
+        let record = records.get_mut(&id)?;
+
+        // Queued commands wait in the outbox until they are sent or expire
+        for delivery in record.targets.values_mut() {
+            if delivery.status == DeliveryStatus::Written
+                && expired(delivery.updated_at, self.timeout)
+            {
+                delivery.status = DeliveryStatus::TimedOut;
+                delivery.updated_at = Utc::now();
+            }
+        }
+        Some(record.clone())
+    }
+
+    /// Waits until every device answered the command or `timeout` elapses,
+    /// waiting longer than the command timeout is pointless.
+    pub async fn wait(&self, id: Uuid, timeout: Duration) -> Option<CommandRecord> {
+        let deadline = tokio::time::Instant::now() + timeout.min(self.timeout);
+        loop {
+            // Registered before checking, so updates in between are not missed
+            let updated = self.updated.notified();
+            tokio::pin!(updated);
+            updated.as_mut().enable();
+
+            let record = self.get(id).await?;
+            if record.is_finished() {
+                return Some(record);
+            }
+
+            if tokio::time::timeout_at(deadline, updated).await.is_err() {
+                return self.get(id).await;
+            }
+        }
+    }
+}
+
+fn expired(since: DateTime<Utc>, duration: Duration) -> bool {
+    let elapsed = Utc::now() - since;
+    elapsed.to_std().is_ok_and(|elapsed| elapsed > duration)
+}
@@ -0,0 +1,387 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow, bail};
+use log::{info, warn};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::client::command::ClientCommand;
+use crate::client::info::RegisteredClientInfo;
+use crate::client::session::SessionSnapshot;
+use crate::server::Server;
+
+const HELP: &str = "\
+list [online|all]         list online or all registered devices
+info <imei>               show a device, its sessions and latest data
+send <targets> <payload>  send a command, targets are `all` or IMEIs and selectors
+                          separated by `,`, such as `tag=fleet,!tag=north` or `8612*`;
+                          the payload may be written as `hex(...)` or `base64(...)`
+kick <imei>               close every session of a device
+tag <imei> +a -b          add or remove tags of a device
+tail <imei> [lines]       show the latest messages of a device, 20 by default
+help                      show this help
+exit                      close this console, the server keeps running";
+
+/// Runs a console line, returns what to print back.
+pub async fn execute(server: &Server, line: &str) -> Result<String> {
+    let line = line.trim();
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim();
+    match name {
+        "" => Ok(String::new()),
+        "help" => Ok(HELP.to_string()),
+        "list" => list(server, args).await,
+        "info" => info(server, imei_arg(args)?).await,
+        "send" => send(server, args).await,
+        "kick" => kick(server, imei_arg(args)?).await,
+        "tag" => tag(args).await,
+        "tail" => tail(server, args).await,
+        name => bail!("unknown command {}, type `help` to list the commands", name),
+    }
+}
+
+fn imei_arg(args: &str) -> Result<&str> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [imei] => Ok(imei),
+        _ => bail!("expected an IMEI"),
+    }
+}
+
+async fn list(server: &Server, args: &str) -> Result<String> {
+    let all = match args {
+        "" | "online" => false,
+        "all" => true,
+        _ => bail!("usage: list [online|all]"),
+    };
+
+    let online = server.list_online_clients_impl().await;
+    let mut rows = vec![
+        [
+            "IMEI",
+            "NAME",
+            "FVER",
+            "CSQ",
+            "STATUS",
+            "ONLINE",
+            "LAST SEEN",
+        ]
+        .map(str::to_string),
+    ];
+    for info in RegisteredClientInfo::load().await? {
+        let session = online.iter().find(|c| c.imei == info.base_info.imei);
+        if !all && session.is_none() {
+            continue;
+        }
+
+        let csq = session
+            .and_then(|c| c.csq)
+            .or(info.last_csq.map(|sample| sample.csq));
+        rows.push([
+            info.base_info.imei.clone(),
+            info.name.clone().unwrap_or("-".to_string()),
+            info.base_info.fver.clone(),
+            csq.map(|csq| csq.to_string()).unwrap_or("-".to_string()),
+            format!("{:?}", info.status).to_lowercase(),
+            if session.is_some() { "yes" } else { "no" }.to_string(),
+            info.last_seen.format("%Y-%m-%d %H:%M:%S").to_string(),
+        ]);
+    }
+
+    let count = rows.len() - 1;
+    Ok(format!("{}{} device(s)", table(&rows), count))
+}
+
+async fn info(server: &Server, imei: &str) -> Result<String> {
+    let info = RegisteredClientInfo::find(imei)
+        .await
+        .ok_or(anyhow!("{} is not registered", imei))?;
+    let sessions: Vec<SessionSnapshot> = server
+        .list_sessions_impl()
+        .await
+        .into_iter()
+        .filter(|session| session.imei == imei)
+        .collect();
+    let online = server
+        .list_online_clients_impl()
+        .await
+        .into_iter()
+        .find(|c| c.imei == imei);
+
+    let mut lines = vec![
+        format!("imei:       {}", info.base_info.imei),
+        format!("name:       {}", info.name.as_deref().unwrap_or("-")),
+        format!("tags:       {}", list_or_dash(&info.tags)),
+        format!("status:     {:?}", info.status).to_lowercase(),
+        format!("iccid:      {}", info.base_info.iccid),
+        format!("fver:       {}", info.base_info.fver),
+    ];
+    if let Some(protocol) = info.protocol {
+        lines.push(format!("protocol:   {}", protocol));
+    }
+    lines.push(format!("sessions:   {}", sessions.len()));
+    for session in &sessions {
+        lines.push(format!(
+            "  session {} from {} since {}, {} bytes, {} frames, {} commands",
+            session.id,
+            session.peer,
+            session.connected_at.format("%Y-%m-%d %H:%M:%S"),
+            session.bytes_received,
+            session.frames_received,
+            session.commands_sent,
+        ));
+    }
+
+    match (online.and_then(|c| c.csq), info.last_csq) {
+        (Some(csq), _) => lines.push(format!("csq:        {}", csq)),
+        (None, Some(sample)) => lines.push(format!(
+            "csq:        {} at {}",
+            sample.csq,
+            sample.time.format("%Y-%m-%d %H:%M:%S")
+        )),
+        (None, None) => {}
+    }
+    let position = server.get_client_positions_impl(imei, 1, false).await;
+    if let Some(position) = position.first()
+        && let (Some(latitude), Some(longitude)) = (position.latitude, position.longitude)
+    {
+        let time = position.time.unwrap_or(position.received_at);
+        lines.push(format!(
+            "position:   {:.6}, {:.6} at {}",
+            latitude,
+            longitude,
+            time.format("%Y-%m-%d %H:%M:%S")
+        ));
+    }
+    let outbox = server.list_outbox_impl(imei).await;
+    if !outbox.is_empty() {
+        lines.push(format!("outbox:     {} command(s)", outbox.len()));
+    }
+    lines.push(format!(
+        "first seen: {}",
+        info.first_seen.format("%Y-%m-%d %H:%M:%S")
+    ));
+    lines.push(format!(
+        "last seen:  {}",
+        info.last_seen.format("%Y-%m-%d %H:%M:%S")
+    ));
+    Ok(lines.join("\n"))
+}
+
+async fn send(server: &Server, args: &str) -> Result<String> {
+    let Some((targets, payload)) = args.split_once(char::is_whitespace) else {
+        bail!("usage: send <targets> <payload>");
+    };
+
+    let command = ClientCommand::from_console(targets, payload.trim())?;
+    if !server.send_command_impl(&command).await {
+        bail!("no active receivers");
+    }
+    Ok(format!("sent command {}", command.id))
+}
+
+async fn kick(server: &Server, imei: &str) -> Result<String> {
+    if !server.kick_client_impl(imei).await {
+        bail!("{} is not online", imei);
+    }
+    Ok(format!("kicked {}", imei))
+}
+
+async fn tag(args: &str) -> Result<String> {
+    let mut args = args.split_whitespace();
+    let (Some(imei), changes) = (args.next(), args) else {
+        bail!("usage: tag <imei> +a -b");
+    };
+    let mut info = RegisteredClientInfo::find(imei)
+        .await
+        .ok_or(anyhow!("{} is not registered", imei))?;
+
+    for change in changes {
+        match change.strip_prefix('-') {
+            Some(tag) => info.tags.retain(|t| t != tag),
+            None => {
+                let tag = change.strip_prefix('+').unwrap_or(change);
+                if !tag.is_empty() && !info.tags.iter().any(|t| t == tag) {
+                    info.tags.push(tag.to_string());
+                }
+            }
+        }
+    }
+    info.save().await?;
+    Ok(format!("tags of {}: {}", imei, list_or_dash(&info.tags)))
+}
+
+async fn tail(server: &Server, args: &str) -> Result<String> {
+    let (imei, count) = match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [imei] => (imei, 20),
+        [imei, count] => (
+            imei,
+            count
+                .parse()
+                .map_err(|_| anyhow!("invalid line count {}", count))?,
+        ),
+        _ => bail!("usage: tail <imei> [lines]"),
+    };
+
+    let log = server
+        .get_client_log_impl(imei)
+        .await
+        .ok_or(anyhow!("no messages from {}", imei))?;
+    let lines: Vec<&str> = log.lines().collect();
+    let skip = lines.len().saturating_sub(count);
+    Ok(lines[skip..].join("\n"))
+}
+
+fn list_or_dash(items: &[String]) -> String {
+    match items {
+        [] => "-".to_string(),
+        items => items.join(", "),
+    }
+}
+
+/// Left aligned columns, with the first row as the header.
+fn table<const N: usize>(rows: &[[String; N]]) -> String {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Executes lines from `input` until it is closed or `exit` is read.
+async fn serve(
+    server: &Server,
+    input: impl AsyncBufRead + Unpin,
+    mut output: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    let mut lines = input.lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim() == "exit" {
+            break;
+        }
+
+        let mut reply = match execute(server, &line).await {
+            Ok(reply) => reply,
+            Err(e) => format!("error: {}", e),
+        };
+        if reply.is_empty() {
+            continue;
+        }
+        reply.push('\n');
+        output.write_all(reply.as_bytes()).await?;
+        output.flush().await?;
+    }
+    Ok(())
+}
+
+pub async fn stdin_loop(server: Arc<Server>) -> Result<()> {
+    let stdin = BufReader::new(tokio::io::stdin());
+    serve(&server, stdin, tokio::io::stdout()).await?;
+
+    // The server keeps running until it receives a shutdown signal
+    info!(target: "console", "stdin closed.");
+    Ok(())
+}
+
+/// Offers the console over a Unix socket until the server shuts down.
+#[cfg(unix)]
+pub async fn socket_loop(server: Arc<Server>, path: String) -> Result<()> {
+    let listener = bind_socket(&path).await?;
+    info!(target: "console", "admin console listening at {}", path);
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => accepted?.0,
+            _ = server.wait_for_shutdown() => break,
+        };
+
+        let server = server.clone();
+        tokio::spawn(async move {
+            info!(target: "console", "admin console attached");
+            let (input, output) = stream.into_split();
+            if let Err(e) = serve(&server, BufReader::new(input), output).await {
+                warn!(target: "console", "admin console failed: {}", e);
+            }
+            info!(target: "console", "admin console detached");
+        });
+    }
+
+    tokio::fs::remove_file(&path).await.ok();
+    Ok(())
+}
+
+/// Binds the socket in a private directory and moves it to `path` once only the user
+/// running the server may connect, replacing a socket left behind by a previous run.
+#[cfg(unix)]
+async fn bind_socket(path: &str) -> Result<tokio::net::UnixListener> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    use tokio::fs;
+    use tokio::net::{UnixListener, UnixStream};
+
+    if let Ok(metadata) = fs::symlink_metadata(path).await {
+        if !metadata.file_type().is_socket() {
+            bail!("{} already exists and is not a socket", path);
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("another server is already listening at {}", path);
+        }
+    }
+
+    let staging = format!("{}.{}", path, std::process::id());
+    fs::DirBuilder::new().mode(0o700).create(&staging).await?;
+    let staged = format!("{}/socket", staging);
+    let result = async {
+        let listener = UnixListener::bind(&staged)?;
+        fs::set_permissions(&staged, Permissions::from_mode(0o600)).await?;
+        fs::rename(&staged, path).await?;
+        Ok(listener)
+    }
+    .await;
+    fs::remove_dir_all(&staging).await.ok();
+    result
+}
+
+/// Connects stdin and stdout to the admin console of a running server.
+#[cfg(unix)]
+pub async fn attach(path: &str) -> Result<()> {
+    use tokio::net::UnixStream;
+
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("failed to connect to {}: {}", path, e))?;
+    let (mut input, mut output) = stream.into_split();
+
+    let mut replies =
+        tokio::spawn(async move { tokio::io::copy(&mut input, &mut tokio::io::stdout()).await });
+    let mut stdin = tokio::io::stdin();
+    tokio::select! {
+        sent = tokio::io::copy(&mut stdin, &mut output) => {
+            sent?;
+            // Replies to the last lines still have to be printed
+            output.shutdown().await?;
+            replies.await??;
+        }
+        received = &mut replies => {
+            received??;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn attach(_path: &str) -> Result<()> {
+    bail!("the admin console is only available on Unix")
+}
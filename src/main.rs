@@ -1,24 +1,53 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
-use log::{error, info};
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::sync::broadcast;
+use anyhow::{Result, anyhow, bail};
+use log::{error, info, warn};
 
 #[cfg(feature = "rest")]
 use crate::server::rest::RestServer;
 
 mod client {
+    pub mod at;
     pub mod command;
+    pub mod framing;
     pub mod handler;
     pub mod info;
+    pub mod nmea;
+    pub mod outbox;
+    pub mod position;
+    pub mod protocol;
+    pub mod selector;
+    pub mod session;
+    pub mod signal;
+    pub mod template;
+    pub mod tracker;
 }
+mod console;
 mod server;
 mod settings;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let mut headless = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "attach" => {
+                attach(args.next()).await?;
+                // The runtime would otherwise wait for the blocking read of stdin
+                std::process::exit(0);
+            }
+            "--headless" => headless = true,
+            arg => bail!(
+                "unknown argument {}, expected `attach [socket]` or `--headless`",
+                arg
+            ),
+        }
+    }
+
     println!(
         "Starting {} (version {})...",
         env!("CARGO_PKG_NAME"),
@@ -30,51 +59,90 @@ async fn main() -> Result<()> {
     info!(target: "main", "loading settings from settings.json");
     let settings = settings::Settings::load().await?;
 
-    let (command_tx, _) = broadcast::channel::<client::command::ClientCommand>(16);
-
-    let server = Arc::new(server::Server::new(settings.clone(), command_tx.clone()));
+    let server = Arc::new(server::Server::new(settings.clone()));
 
     // Start TCP server loop
     let tcp_server = server.clone();
-    info!(target: "main", "starting TCP server at {}", settings.address);
+    info!(target: "main", "starting TCP server with {} listener(s)", settings.listeners.len());
     tokio::spawn(async move { tcp_server.server_loop().await.expect("server loop error") });
 
-    // Start REST server
+    // Start REST server, it waits for `rest.enabled` while disabled
     #[cfg(feature = "rest")]
-    if settings.rest.enabled {
+    {
         let rest_server = server.clone();
-        info!(target: "main", "starting REST server at {}", settings.rest.address);
+        info!(target: "main", "starting REST server");
         tokio::spawn(async move { rest_server.serve_rest().await.expect("REST server error") });
     }
 
     // Start console input loop
-    info!(target: "main", "starting console input loop");
-    console_loop(command_tx).await.expect("console loop error");
+    if settings.console.stdin && !headless {
+        info!(target: "main", "starting console input loop");
+        let console_server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = console::stdin_loop(console_server).await {
+                error!(target: "main", "console loop error: {}", e);
+            }
+        });
+    }
 
-    Ok(())
-}
+    // Start admin console socket, awaited on shutdown so it removes its socket file
+    let mut console_sockets = tokio::task::JoinSet::new();
+    if let Some(path) = settings.console.socket_path.clone() {
+        #[cfg(unix)]
+        {
+            let console_server = server.clone();
+            console_sockets.spawn(async move {
+                if let Err(e) = console::socket_loop(console_server, path).await {
+                    error!(target: "main", "admin console error: {}", e);
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        warn!(target: "main", "ignoring console socket {}, only available on Unix", path);
+    }
 
-async fn console_loop(command_tx: broadcast::Sender<client::command::ClientCommand>) -> Result<()> {
-    let mut stdin = BufReader::new(tokio::io::stdin());
-    let mut line = String::new();
+    shutdown_signal().await?;
+    let deadline = Duration::from_secs(server.settings().shutdown.deadline_sec);
+    if tokio::time::timeout(deadline, server.shutdown())
+        .await
+        .is_err()
+    {
+        warn!(target: "main", "connections still open after {:?}, exiting", deadline);
+    }
+    console_sockets.join_all().await;
 
-    loop {
-        line.clear();
-        let size = stdin.read_line(&mut line).await;
-        if let Err(e) = size {
-            error!(target: "console", "failed to read from stdin: {}", e);
-            return Err(e.into());
-        }
+    // The runtime would otherwise wait for the console's blocking read of stdin
+    std::process::exit(0);
+}
 
-        let size = size?;
-        if size == 0 {
-            info!(target: "console", "stdin closed.");
-            return Ok(());
-        }
+/// Attaches to the admin console socket given or configured in settings.json.
+async fn attach(path: Option<String>) -> Result<()> {
+    let path = match path {
+        Some(path) => path,
+        None => settings::Settings::load()
+            .await?
+            .console
+            .socket_path
+            .ok_or(anyhow!("no console.socket_path in settings.json"))?,
+    };
+    console::attach(&path).await
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
 
-        let command = line.trim().parse()?;
-        if command_tx.send(command).is_err() {
-            info!(target: "console", "no active receivers");
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
         }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    info!(target: "main", "received shutdown signal");
+    Ok(())
 }
@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, RwLock, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use super::Server;
+use crate::client::command::ClientCommand;
+use crate::client::tracker::{Delivery, DeliveryStatus};
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct BulkJobRequest {
+    /// Sent to each device separately, `target` or `select` is required
+    #[serde(flatten)]
+    pub command: ClientCommand,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Devices of a batch waiting for an answer at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Pauses the job once the share of failed devices exceeds it, from 0 to 1
+    pub max_failure_rate: Option<f64>,
+    #[serde(default)]
+    pub batch_interval_sec: u64,
+}
+
+fn default_batch_size() -> usize {
+    50
+}
+
+fn default_concurrency() -> usize {
+    10
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkJobStatus {
+    Running,
+    /// Waiting to be resumed, after a failure rate above the limit or on request
+    Paused,
+    Cancelled,
+    Completed,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceProgressStatus {
+    Pending,
+    Sent,
+    Acked,
+    Failed,
+    /// Queued in the outbox until the device connects, updated once it answers
+    Offline,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceProgress {
+    pub status: DeviceProgressStatus,
+    pub command_id: Option<Uuid>,
+    pub reply: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ProgressCounts {
+    pub pending: usize,
+    pub sent: usize,
+    pub acked: usize,
+    pub failed: usize,
+    pub offline: usize,
+}
+
+impl ProgressCounts {
+    fn failure_rate(&self) -> f64 {
+        let done = self.acked + self.failed;
+        match done {
+            0 => 0.0,
+            _ => self.failed as f64 / done as f64,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BulkJobSnapshot {
+    pub id: Uuid,
+    pub command: String,
+    pub status: BulkJobStatus,
+    pub batch_size: usize,
+    pub concurrency: usize,
+    pub max_failure_rate: Option<f64>,
+    pub batches_done: usize,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub counts: ProgressCounts,
+    /// Left out of job listings
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub devices: BTreeMap<String, DeviceProgress>,
+}
+
+impl BulkJobSnapshot {
+    fn update_counts(&mut self) {
+        let mut counts = ProgressCounts::default();
+        for progress in self.devices.values() {
+            match progress.status {
+                DeviceProgressStatus::Pending => counts.pending += 1,
+                DeviceProgressStatus::Sent => counts.sent += 1,
+                DeviceProgressStatus::Acked => counts.acked += 1,
+                DeviceProgressStatus::Failed => counts.failed += 1,
+                DeviceProgressStatus::Offline => counts.offline += 1,
+            }
+        }
+        self.counts = counts;
+    }
+}
+
+struct BulkJob {
+    request: BulkJobRequest,
+    state: RwLock<BulkJobSnapshot>,
+    resumed: Notify,
+}
+
+impl BulkJob {
+    async fn status(&self) -> BulkJobStatus {
+        self.state.read().await.status
+    }
+
+    async fn set_status(&self, status: BulkJobStatus) {
+        let mut state = self.state.write().await;
+        state.status = status;
+        if matches!(status, BulkJobStatus::Cancelled | BulkJobStatus::Completed) {
+            state.finished_at = Some(Utc::now());
+        }
+    }
+
+    async fn update(&self, id: &str, status: DeviceProgressStatus, reply: Option<String>) {
+        let mut state = self.state.write().await;
+        if let Some(progress) = state.devices.get_mut(id) {
+            progress.status = status;
+            progress.reply = reply;
+            progress.updated_at = Utc::now();
+        }
+        state.update_counts();
+    }
+}
+
+/// Commands rolled out to many devices in batches, kept until the server stops.
+#[derive(Default)]
+pub struct BulkJobs {
+    jobs: RwLock<HashMap<Uuid, Arc<BulkJob>>>,
+}
+
+impl BulkJobs {
+    pub async fn list(&self) -> Vec<BulkJobSnapshot> {
+        let jobs = self.jobs.read().await;
+        let mut snapshots = Vec::new();
+        for job in jobs.values() {
+            let mut snapshot = job.state.read().await.clone();
+            snapshot.devices.clear();
+            snapshots.push(snapshot);
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.created_at);
+        snapshots
+    }
+
+    pub async fn get(&self, id: Uuid) -> Option<BulkJobSnapshot> {
+        let job = self.jobs.read().await.get(&id).cloned()?;
+        let snapshot = job.state.read().await.clone();
+        Some(snapshot)
+    }
+
+    pub async fn resume(&self, id: Uuid) -> bool {
+        let Some(job) = self.jobs.read().await.get(&id).cloned() else {
+            return false;
+        };
+        if job.status().await != BulkJobStatus::Paused {
+            return false;
+        }
+
+        job.set_status(BulkJobStatus::Running).await;
+        job.resumed.notify_one();
+        true
+    }
+
+    pub async fn pause(&self, id: Uuid) -> bool {
+        let Some(job) = self.jobs.read().await.get(&id).cloned() else {
+            return false;
+        };
+        if job.status().await != BulkJobStatus::Running {
+            return false;
+        }
+
+        job.set_status(BulkJobStatus::Paused).await;
+        true
+    }
+
+    /// Stops the job before its next batch, devices of the current one still get the command.
+    pub async fn cancel(&self, id: Uuid) -> bool {
+        let Some(job) = self.jobs.read().await.get(&id).cloned() else {
+            return false;
+        };
+        if !matches!(
+            job.status().await,
+            BulkJobStatus::Running | BulkJobStatus::Paused
+        ) {
+            return false;
+        }
+
+        job.set_status(BulkJobStatus::Cancelled).await;
+        job.resumed.notify_one();
+        true
+    }
+}
+
+/// Resolves the targets of the job and starts rolling it out.
+pub async fn start(server: Arc<Server>, request: BulkJobRequest) -> Result<BulkJobSnapshot> {
+    if request.command.is_broadcast() {
+        bail!("bulk jobs need a target or select");
+    }
+    if request.batch_size == 0 || request.concurrency == 0 {
+        bail!("batch_size and concurrency must be at least 1");
+    }
+    if let Some(max) = request.max_failure_rate
+        && !(0.0..=1.0).contains(&max)
+    {
+        bail!("max_failure_rate must be between 0 and 1, got {}", max);
+    }
+
+    let resolved = server.resolve_command_impl(&request.command).await;
+    if resolved.targets().is_empty() {
+        bail!("no devices match the job");
+    }
+
+    let now = Utc::now();
+    let devices = resolved
+        .targets()
+        .iter()
+        .map(|id| {
+            let progress = DeviceProgress {
+                status: DeviceProgressStatus::Pending,
+                command_id: None,
+                reply: None,
+                updated_at: now,
+            };
+            (id.clone(), progress)
+        })
+        .collect();
+    let mut snapshot = BulkJobSnapshot {
+        id: Uuid::new_v4(),
+        command: request.command.summary(),
+        status: BulkJobStatus::Running,
+        batch_size: request.batch_size,
+        concurrency: request.concurrency,
+        max_failure_rate: request.max_failure_rate,
+        batches_done: 0,
+        created_at: now,
+        finished_at: None,
+        counts: ProgressCounts::default(),
+        devices,
+    };
+    snapshot.update_counts();
+
+    let job = Arc::new(BulkJob {
+        request,
+        state: RwLock::new(snapshot.clone()),
+        resumed: Notify::new(),
+    });
+    server
+        .bulk_jobs
+        .jobs
+        .write()
+        .await
+        .insert(snapshot.id, job.clone());
+
+    let targets = resolved.targets().to_vec();
+    info!(target: "bulk", "starting job {} for {} device(s)", snapshot.id, targets.len());
+    tokio::spawn(run(server, job, snapshot.id, targets));
+    Ok(snapshot)
+}
+
+async fn run(server: Arc<Server>, job: Arc<BulkJob>, id: Uuid, targets: Vec<String>) {
+    let request = &job.request;
+    let batch_interval = Duration::from_secs(request.batch_interval_sec);
+
+    for (index, batch) in targets.chunks(request.batch_size).enumerate() {
+        // Paused jobs wait here, a cancel wakes them up to stop
+        loop {
+            match job.status().await {
+                BulkJobStatus::Running => break,
+                BulkJobStatus::Paused => job.resumed.notified().await,
+                BulkJobStatus::Cancelled | BulkJobStatus::Completed => return,
+            }
+        }
+        if index > 0 && !batch_interval.is_zero() {
+            tokio::time::sleep(batch_interval).await;
+        }
+
+        let semaphore = Arc::new(Semaphore::new(request.concurrency));
+        let mut tasks = JoinSet::new();
+        for target in batch {
+            let server = server.clone();
+            let job = job.clone();
+            let semaphore = semaphore.clone();
+            let target = target.clone();
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire().await;
+                if let Some(command_id) = send(&server, &job, &target).await {
+                    // Answers of offline devices count once they connect, even after later batches
+                    tokio::spawn(watch_offline(server, job, target, command_id));
+                }
+            });
+        }
+        tasks.join_all().await;
+
+        let mut state = job.state.write().await;
+        state.batches_done += 1;
+        let failure_rate = state.counts.failure_rate();
+        let remaining = state.counts.pending > 0;
+        if remaining
+            && state.status == BulkJobStatus::Running
+            && request
+                .max_failure_rate
+                .is_some_and(|max| failure_rate > max)
+        {
+            warn!(target: "bulk", "pausing job {}, failure rate {:.2} is too high", id, failure_rate);
+            state.status = BulkJobStatus::Paused;
+        }
+    }
+
+    if job.status().await != BulkJobStatus::Cancelled {
+        job.set_status(BulkJobStatus::Completed).await;
+    }
+    info!(target: "bulk", "job {} finished", id);
+}
+
+/// Returns the command ID when it is queued in the outbox of an offline device.
+async fn send(server: &Server, job: &BulkJob, target: &str) -> Option<Uuid> {
+    let command = job.request.command.for_target(target);
+    if let Some(progress) = job.state.write().await.devices.get_mut(target) {
+        progress.command_id = Some(command.id);
+    }
+
+    // Commands routed to a session are queued too until they are written
+    let online = server.sessions.get(target).await.is_some();
+    server.send_command_impl(&command).await;
+    if online {
+        job.update(target, DeviceProgressStatus::Sent, None).await;
+    }
+
+    let wait = online.then_some(Duration::MAX);
+    let record = server.get_command_impl(command.id, wait).await;
+    let delivery = record.and_then(|record| record.targets.get(target).cloned());
+    if delivery
+        .as_ref()
+        .is_some_and(|delivery| delivery.status == DeliveryStatus::Queued)
+    {
+        job.update(target, DeviceProgressStatus::Offline, None)
+            .await;
+        return Some(command.id);
+    }
+
+    let (status, reply) = progress_of(delivery);
+    job.update(target, status, reply).await;
+    None
+}
+
+/// Waits for a command queued in the outbox until it is answered or its TTL expires.
+async fn watch_offline(server: Arc<Server>, job: Arc<BulkJob>, target: String, id: Uuid) {
+    let expires_at = job
+        .request
+        .command
+        .ttl_sec
+        .map(|ttl| Instant::now() + Duration::from_secs(ttl));
+
+    loop {
+        // The tracker caps each wait at the command timeout
+        let record = server.get_command_impl(id, Some(Duration::MAX)).await;
+        let Some(delivery) = record.and_then(|record| record.targets.get(&target).cloned()) else {
+            // Forgotten by the tracker, the last known progress stays
+            return;
+        };
+
+        match delivery.status {
+            DeliveryStatus::Queued if expires_at.is_some_and(|at| at <= Instant::now()) => {
+                let reply = Some("expired in the outbox".to_string());
+                job.update(&target, DeviceProgressStatus::Failed, reply)
+                    .await;
+                return;
+            }
+            DeliveryStatus::Queued => {}
+            DeliveryStatus::Written => {
+                job.update(&target, DeviceProgressStatus::Sent, None).await;
+            }
+            _ => {
+                let (status, reply) = progress_of(Some(delivery));
+                job.update(&target, status, reply).await;
+                return;
+            }
+        }
+    }
+}
+
+fn progress_of(delivery: Option<Delivery>) -> (DeviceProgressStatus, Option<String>) {
+    match delivery {
+        Some(delivery) => match delivery.status {
+            DeliveryStatus::Queued => (DeviceProgressStatus::Offline, None),
+            DeliveryStatus::Acknowledged | DeliveryStatus::Replied => {
+                (DeviceProgressStatus::Acked, delivery.reply)
+            }
+            DeliveryStatus::Failed => (DeviceProgressStatus::Failed, delivery.reply),
+            DeliveryStatus::Written | DeliveryStatus::TimedOut => {
+                (DeviceProgressStatus::Failed, Some("timed out".to_string()))
+            }
+        },
+        None => {
+            let reply = Some("failed to dispatch command".to_string());
+            (DeviceProgressStatus::Failed, reply)
+        }
+    }
+}
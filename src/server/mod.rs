@@ -1,92 +1,791 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Result;
-use log::{debug, warn};
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
 use tokio::fs;
-use tokio::net::TcpListener;
-use tokio::sync::{RwLock, broadcast};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{RwLock, watch};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio::time;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
 
-use crate::client::command::ClientCommand;
-use crate::client::handler::{self, ClientHandler};
-use crate::client::info::ClientInfo;
-use crate::settings::Settings;
+use crate::client::at::{self, AtResult};
+use crate::client::command::{ClientCommand, CommandKind};
+use crate::client::handler::{self, ClientHandler, ClientStream};
+use crate::client::info::{ClientInfo, DeviceStatus, RegisteredClientInfo};
+use crate::client::outbox::{Outbox, OutboxItem};
+use crate::client::position::Position;
+use crate::client::session::{SessionRegistry, SessionSnapshot};
+use crate::client::signal::{self, CsqSample, CsqStats, StatsPeriod};
+use crate::client::template::{CommandProfile, TemplateCall};
+use crate::client::tracker::{CommandRecord, CommandTracker, DeliveryStatus};
+use crate::settings::{ListenerConfig, Settings, Transport};
+use bulk::{BulkJobRequest, BulkJobSnapshot, BulkJobs};
+use scheduler::{Job, JobRequest, Scheduler};
 
+pub mod bulk;
+mod reload;
 #[cfg(feature = "rest")]
 pub mod rest;
+pub mod scheduler;
+#[cfg(feature = "tls")]
+mod tls;
+mod udp;
 
 pub struct Server {
-    settings: Settings,
-    command_tx: broadcast::Sender<ClientCommand>,
-    online_clients: Arc<RwLock<Vec<ClientInfo>>>,
+    /// Replaced as a whole when settings.json is reloaded
+    settings: watch::Sender<Arc<Settings>>,
+    sessions: Arc<SessionRegistry>,
+    commands: Arc<CommandTracker>,
+    outbox: Arc<Outbox>,
+    scheduler: Arc<Scheduler>,
+    bulk_jobs: Arc<BulkJobs>,
+    shutdown: watch::Sender<bool>,
+    /// Held for reading by every connection, taken for writing once they are all closed
+    connections: Arc<RwLock<()>>,
 }
 
 impl Server {
-    pub fn new(settings: Settings, command_tx: broadcast::Sender<ClientCommand>) -> Self {
+    pub fn new(settings: Settings) -> Self {
+        let command_timeout = Duration::from_secs(settings.command_timeout_sec);
+        let outbox = Outbox::new(&settings.output_dir);
+        let scheduler = Scheduler::new(&settings.output_dir);
         Self {
-            settings,
-            command_tx,
-            online_clients: Arc::new(RwLock::new(Vec::new())),
+            settings: watch::Sender::new(Arc::new(settings)),
+            sessions: Arc::new(SessionRegistry::default()),
+            commands: Arc::new(CommandTracker::new(command_timeout)),
+            outbox: Arc::new(outbox),
+            scheduler: Arc::new(scheduler),
+            bulk_jobs: Arc::new(BulkJobs::default()),
+            shutdown: watch::Sender::new(false),
+            connections: Arc::new(RwLock::new(())),
         }
     }
 
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.borrow().clone()
+    }
+
+    pub fn subscribe_settings(&self) -> watch::Receiver<Arc<Settings>> {
+        self.settings.subscribe()
+    }
+
+    /// Loads settings.json again, the current settings are kept if it is invalid.
+    pub async fn reload_settings_impl(&self) -> Result<()> {
+        let mut settings = Settings::load().await?;
+        for field in settings.keep_startup_fields(&self.settings()) {
+            warn!(target: "server", "{} changed, it is only applied on restart", field);
+        }
+
+        self.settings.send_replace(Arc::new(settings));
+        info!(target: "server", "reloaded settings");
+        Ok(())
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolves once [`Server::shutdown`] has been called.
+    pub async fn wait_for_shutdown(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        shutdown.wait_for(|shutdown| *shutdown).await.ok();
+    }
+
+    /// Stops accepting devices and REST requests, sends the goodbye command
+    /// and waits for every connection to close.
+    pub async fn shutdown(&self) {
+        info!(target: "server", "shutting down");
+        self.shutdown.send_replace(true);
+
+        if let Some(goodbye) = &self.settings().shutdown.goodbye_command {
+            let command = ClientCommand::new_broadcast(goodbye.clone());
+            self.send_command_impl(&command).await;
+        }
+        self.sessions.close_all().await;
+
+        // Handlers save the registered infos and close their files as they exit
+        let _ = self.connections.write().await;
+        info!(target: "server", "all connections closed");
+    }
+
     pub async fn list_online_clients_impl(&self) -> Vec<ClientInfo> {
         debug!(target: "server", "listing online clients");
-        self.online_clients.read().await.clone()
+        self.sessions.infos().await
+    }
+
+    pub async fn list_sessions_impl(&self) -> Vec<SessionSnapshot> {
+        debug!(target: "server", "listing sessions");
+        self.sessions.snapshots().await
+    }
+
+    pub async fn kick_client_impl(&self, imei: &str) -> bool {
+        debug!(target: "server", "kicking imei: {}", imei);
+        self.sessions.kick(imei, "kicked through the API").await
     }
 
     pub async fn get_client_log_impl(&self, imei: &str) -> Option<String> {
         debug!(target: "server", "getting client log for imei: {}", imei);
-        let log_path = handler::log_path(&self.settings.output_dir, imei);
+        let log_path = handler::log_path(&self.settings().output_dir, imei).ok()?;
         fs::read_to_string(&log_path).await.ok()
     }
 
-    pub fn send_command_impl(&self, command: &ClientCommand) -> bool {
-        debug!(target: "server", "sending command: {}", command);
+    pub async fn get_client_positions_impl(
+        &self,
+        imei: &str,
+        limit: usize,
+        alarms_only: bool,
+    ) -> Vec<Position> {
+        debug!(target: "server", "getting client positions for imei: {}", imei);
+        let Ok(path) = handler::positions_path(&self.settings().output_dir, imei) else {
+            return Vec::new();
+        };
+        let Ok(content) = fs::read_to_string(&path).await else {
+            return Vec::new();
+        };
+
+        let positions: Vec<Position> = content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Position>(line).ok())
+            .filter(|position| !alarms_only || position.has_alarm())
+            .collect();
+        let skip = positions.len().saturating_sub(limit);
+        positions.into_iter().skip(skip).collect()
+    }
+
+    /// Signal quality samples of the device between `since` and `until`, the latest `limit` ones.
+    pub async fn get_client_csq_impl(
+        &self,
+        imei: &str,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Vec<CsqSample> {
+        debug!(target: "server", "getting client signal quality for imei: {}", imei);
+        let Ok(path) = handler::csq_path(&self.settings().output_dir, imei) else {
+            return Vec::new();
+        };
+        let Ok(content) = fs::read_to_string(&path).await else {
+            return Vec::new();
+        };
+
+        let samples: Vec<CsqSample> = content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<CsqSample>(line).ok())
+            .filter(|sample| since.is_none_or(|since| sample.time >= since))
+            .filter(|sample| until.is_none_or(|until| sample.time < until))
+            .collect();
+        let skip = samples.len().saturating_sub(limit);
+        samples.into_iter().skip(skip).collect()
+    }
+
+    pub async fn get_client_csq_stats_impl(
+        &self,
+        imei: &str,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        period: StatsPeriod,
+    ) -> Vec<CsqStats> {
+        let samples = self
+            .get_client_csq_impl(imei, since, until, usize::MAX)
+            .await;
+        signal::stats(&samples, period)
+    }
+
+    pub async fn send_command_impl(&self, command: &ClientCommand) -> bool {
+        debug!(target: "server", "sending command {}: {}", command.id, command);
+
+        // A selector matching nothing must not turn into a broadcast
+        let resolved = &self.resolve_command_impl(command).await;
+        if !command.select.is_empty() && resolved.is_broadcast() {
+            warn!(target: "server", "no devices match command: {}", command);
+            return false;
+        }
+        let command = resolved;
+        self.commands.track(command).await;
+
+        // Broadcasts go to every connected device, and are not kept for offline ones
+        if command.is_broadcast() {
+            let ids = self.sessions.ids().await;
+            if ids.is_empty() {
+                warn!(target: "server", "no active receivers for command: {}", command);
+                return false;
+            }
+            for id in ids {
+                if let Ok(command) = self.prepare_command(&id, command).await {
+                    self.route_command(&id, &command).await.ok();
+                }
+            }
+            return true;
+        }
+
+        let mut success = true;
+        for id in command.targets() {
+            let Ok(command) = self.prepare_command(id, command).await else {
+                success = false;
+                continue;
+            };
+            match self.route_command(id, &command).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(_) => {
+                    success = false;
+                    continue;
+                }
+            }
+
+            // Mistyped or made up targets would leave outbox files that never drain
+            if RegisteredClientInfo::find(id).await.is_none() {
+                warn!(target: "server", "not queueing command {} for unregistered {}", command.id, id);
+                let reply = Some(format!("{} is not registered", id));
+                self.commands
+                    .update(command.id, id, DeliveryStatus::Failed, reply)
+                    .await;
+                success = false;
+                continue;
+            }
+            match self.outbox.push(id, &command).await {
+                Ok(()) => {
+                    info!(target: "server", "queued command {} for offline {}", command.id, id)
+                }
+                Err(e) => {
+                    warn!(target: "server", "failed to queue command for {}: {}", id, e);
+                    let reply = Some(e.to_string());
+                    self.commands
+                        .update(command.id, id, DeliveryStatus::Failed, reply)
+                        .await;
+                    success = false;
+                }
+            }
+        }
+        success
+    }
+
+    /// Replaces the command's selector with the approved devices it matches.
+    pub async fn resolve_command_impl(&self, command: &ClientCommand) -> ClientCommand {
+        if command.select.is_empty() {
+            return command.clone();
+        }
+
+        let registered = RegisteredClientInfo::load().await.unwrap_or_default();
+        let mut selected = Vec::new();
+        for info in registered {
+            if info.status != DeviceStatus::Approved {
+                continue;
+            }
+
+            let id = info.base_info.imei.clone();
+            let fver = match self.sessions.get(&id).await {
+                Some(session) => session.info.fver,
+                None => info.base_info.fver.clone(),
+            };
+            if command.select.matches(&info, &fver) {
+                selected.push(id);
+            }
+        }
+
+        let resolved = command.resolve(selected);
+        debug!(target: "server", "resolved command {} to {}", command.id, resolved);
+        resolved
+    }
+
+    /// Renders the command for the device's firmware, failing its delivery when it cannot be sent.
+    async fn prepare_command(&self, id: &str, command: &ClientCommand) -> Result<ClientCommand> {
+        let fver = self.device_fver(id).await;
+        let settings = self.settings();
+        let profile = fver.and_then(|fver| settings.command_profile(&fver));
+        let result = render_command(id, profile, command);
+        if let Err(e) = &result {
+            warn!(target: "server", "refused command {} for {}: {}", command.id, id, e);
+            let reply = Some(e.to_string());
+            self.commands
+                .update(command.id, id, DeliveryStatus::Failed, reply)
+                .await;
+        }
+        result
+    }
+
+    /// Firmware version of the device's session, or the one it last logged in with.
+    async fn device_fver(&self, id: &str) -> Option<String> {
+        if let Some(session) = self.sessions.get(id).await {
+            return Some(session.info.fver);
+        }
+        let info = RegisteredClientInfo::find(id).await?;
+        Some(info.base_info.fver)
+    }
+
+    pub fn render_template_impl(&self, fver: &str, call: &TemplateCall) -> Result<String> {
+        debug!(target: "server", "rendering template {} for fver: {}", call, fver);
+        let settings = self.settings();
+        let profile = settings
+            .command_profile(fver)
+            .ok_or(anyhow!("no command profile for firmware {}", fver))?;
+        profile.render(call)
+    }
+
+    pub async fn get_client_fver_impl(&self, imei: &str) -> Option<String> {
+        self.device_fver(imei).await
+    }
 
-        let send_err = self.command_tx.send(command.clone()).err();
-        if send_err.is_some() {
-            warn!(target: "server", "no active receivers for command: {}", command);
+    /// Hands the command to the device's session, false when the device is offline.
+    /// A full queue fails the delivery, so a device not reading its commands holds back no one else.
+    async fn route_command(&self, id: &str, command: &ClientCommand) -> Result<bool> {
+        let Some(session) = self.sessions.get(id).await else {
+            return Ok(false);
+        };
+        match session.commands.try_send(command.clone()) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Closed(_)) => Ok(false),
+            Err(TrySendError::Full(_)) => {
+                warn!(target: "server", "command queue of {} is full, dropping command {}", id, command.id);
+                let reply = format!("command queue of {} is full", id);
+                self.commands
+                    .update(command.id, id, DeliveryStatus::Failed, Some(reply.clone()))
+                    .await;
+                Err(anyhow!(reply))
+            }
         }
-        send_err.is_none()
     }
 
-    pub async fn server_loop(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.settings.address).await.unwrap();
+    /// Sends an AT command to an online device and waits for its complete response,
+    /// at most for the command timeout as the device is given up on after it.
+    pub async fn send_at_command_impl(
+        &self,
+        imei: &str,
+        at_command: &str,
+        wait: Option<Duration>,
+    ) -> Result<AtResult> {
+        debug!(target: "server", "sending AT command to imei {}: {}", imei, at_command);
+        if self.sessions.get(imei).await.is_none() {
+            bail!("{} is offline", imei);
+        }
 
-        let output_dir = &self.settings.output_dir;
-        if !fs::try_exists(output_dir).await.unwrap_or(false) {
-            fs::create_dir_all(output_dir).await?;
+        let mut command = ClientCommand::new(vec![imei.to_string()], at_command.to_string());
+        command.kind = CommandKind::At;
+        // Expires right away if the device disconnects before it is written
+        command.ttl_sec = Some(0);
+        if !self.send_command_impl(&command).await {
+            bail!("failed to send AT command to {}", imei);
         }
 
-        let heartbeat_duration = Duration::from_secs(self.settings.heartbeat_sec);
+        let timeout = wait.unwrap_or(self.commands.timeout());
+        let delivery = self
+            .commands
+            .wait(command.id, timeout)
+            .await
+            .and_then(|record| record.targets.get(imei).cloned())
+            .ok_or(anyhow!("AT command to {} was not tracked", imei))?;
+
+        let reply = delivery.reply.unwrap_or_default();
+        Ok(AtResult {
+            id: command.id,
+            status: delivery.status,
+            response: at::parse(at_command, &reply),
+        })
+    }
+
+    pub async fn list_outbox_impl(&self, imei: &str) -> Vec<OutboxItem> {
+        debug!(target: "server", "listing outbox of imei: {}", imei);
+        self.outbox.list(imei).await
+    }
+
+    pub async fn cancel_outbox_impl(&self, imei: &str, id: Uuid) -> Result<bool> {
+        debug!(target: "server", "cancelling command {} for imei: {}", id, imei);
+        let Some(item) = self.outbox.cancel(imei, id).await? else {
+            return Ok(false);
+        };
+
+        let reply = Some("cancelled".to_string());
+        self.commands
+            .update(item.command.id, imei, DeliveryStatus::Failed, reply)
+            .await;
+        Ok(true)
+    }
+
+    pub async fn get_command_impl(
+        &self,
+        id: Uuid,
+        wait: Option<Duration>,
+    ) -> Option<CommandRecord> {
+        match wait {
+            Some(timeout) => self.commands.wait(id, timeout).await,
+            None => self.commands.get(id).await,
+        }
+    }
+
+    pub fn list_command_profiles_impl(&self) -> BTreeMap<String, CommandProfile> {
+        self.settings().command_profiles.clone()
+    }
+
+    pub async fn list_jobs_impl(&self) -> Vec<Job> {
+        debug!(target: "server", "listing jobs");
+        self.scheduler.list().await
+    }
+
+    pub async fn get_job_impl(&self, id: Uuid) -> Option<Job> {
+        debug!(target: "server", "getting job: {}", id);
+        self.scheduler.get(id).await
+    }
+
+    pub async fn create_job_impl(&self, request: JobRequest) -> Result<Job> {
+        debug!(target: "server", "creating job: {}", request.command);
+        self.scheduler.create(request).await
+    }
+
+    pub async fn update_job_impl(&self, id: Uuid, request: JobRequest) -> Result<Option<Job>> {
+        debug!(target: "server", "updating job: {}", id);
+        self.scheduler.update(id, request).await
+    }
+
+    pub async fn delete_job_impl(&self, id: Uuid) -> Result<bool> {
+        debug!(target: "server", "deleting job: {}", id);
+        self.scheduler.delete(id).await
+    }
+
+    pub async fn start_bulk_job_impl(
+        self: Arc<Self>,
+        request: BulkJobRequest,
+    ) -> Result<BulkJobSnapshot> {
+        debug!(target: "server", "starting bulk job: {}", request.command);
+        bulk::start(self, request).await
+    }
+
+    pub async fn list_bulk_jobs_impl(&self) -> Vec<BulkJobSnapshot> {
+        debug!(target: "server", "listing bulk jobs");
+        self.bulk_jobs.list().await
+    }
+
+    pub async fn get_bulk_job_impl(&self, id: Uuid) -> Option<BulkJobSnapshot> {
+        debug!(target: "server", "getting bulk job: {}", id);
+        self.bulk_jobs.get(id).await
+    }
+
+    pub async fn pause_bulk_job_impl(&self, id: Uuid) -> bool {
+        debug!(target: "server", "pausing bulk job: {}", id);
+        self.bulk_jobs.pause(id).await
+    }
+
+    pub async fn resume_bulk_job_impl(&self, id: Uuid) -> bool {
+        debug!(target: "server", "resuming bulk job: {}", id);
+        self.bulk_jobs.resume(id).await
+    }
+
+    pub async fn cancel_bulk_job_impl(&self, id: Uuid) -> bool {
+        debug!(target: "server", "cancelling bulk job: {}", id);
+        self.bulk_jobs.cancel(id).await
+    }
+
+    pub async fn server_loop(self: Arc<Self>) -> Result<()> {
+        let output_dir = &self.settings().output_dir;
+        for dir in [
+            handler::positions_dir(output_dir),
+            handler::csq_dir(output_dir),
+        ] {
+            if !fs::try_exists(&dir).await.unwrap_or(false) {
+                fs::create_dir_all(&dir).await?;
+            }
+        }
+
+        let mut tasks = JoinSet::new();
+        tasks.spawn(scheduler::scheduler_loop(self.clone()));
+        tasks.spawn(reload::reload_loop(self.clone()));
+
+        let mut settings = self.subscribe_settings();
+        let mut listeners = HashMap::new();
+        while !self.is_shutting_down() {
+            let configs = settings.borrow_and_update().listeners.clone();
+            self.update_listeners(&mut listeners, configs).await;
+
+            tokio::select! {
+                _ = settings.changed() => {}
+                Some(result) = tasks.join_next() => result??,
+                _ = self.wait_for_shutdown() => {}
+            }
+        }
+
+        // The listener handles are only dropped here, not aborted: listeners stop accepting
+        // on their own and UDP ones keep serving their sessions until they close
+        tasks.detach_all();
+        Ok(())
+    }
+
+    /// Starts the configured listeners that are not running and stops the ones no longer configured,
+    /// listeners are keyed by their whole config so that any change restarts them.
+    ///
+    /// New listeners are bound before the old ones are stopped, if any of them cannot be bound
+    /// the running listeners are kept as they are.
+    async fn update_listeners(
+        self: &Arc<Self>,
+        listeners: &mut HashMap<String, (ListenerConfig, JoinHandle<()>)>,
+        configs: Vec<ListenerConfig>,
+    ) {
+        let configs: HashMap<String, ListenerConfig> = configs
+            .into_iter()
+            .map(|config| (serde_json::to_string(&config).unwrap_or_default(), config))
+            .collect();
+
+        // A listener that failed is started again
+        listeners.retain(|_, (_, task)| !task.is_finished());
+        let removed: Vec<String> = listeners
+            .keys()
+            .filter(|key| !configs.contains_key(*key))
+            .cloned()
+            .collect();
+        let held: Vec<(String, Transport)> = removed
+            .iter()
+            .filter_map(|key| listeners.get(key))
+            .map(|(config, _)| (config.address.clone(), config.transport))
+            .collect();
+
+        let mut added = Vec::new();
+        for (key, config) in configs {
+            if listeners.contains_key(&key) {
+                continue;
+            }
+
+            // An address still held by a listener being replaced can only be bound once it stops
+            let address = (config.address.clone(), config.transport);
+            let bound = match held.contains(&address) {
+                true => check_listener(&config).map(|()| None),
+                false => bind_listener(&config).await.map(Some),
+            };
+            match bound {
+                Ok(bound) => added.push((key, config, bound)),
+                Err(e) => {
+                    error!(target: "server", "failed to listen at {}, keeping the current listeners: {}", config.address, e);
+                    return;
+                }
+            }
+        }
+
+        let mut stopped = HashMap::new();
+        for key in removed {
+            if let Some((config, task)) = listeners.remove(&key) {
+                task.abort();
+                task.await.ok();
+                info!(target: "server", "stopped listening at {}", config.address);
+                stopped.insert((config.address.clone(), config.transport), (key, config));
+            }
+        }
+
+        for (key, config, bound) in added {
+            let bound = match bound {
+                Some(bound) => bound,
+                None => match bind_listener(&config).await {
+                    Ok(bound) => bound,
+                    Err(e) => {
+                        error!(target: "server", "failed to listen at {}, restoring the previous listener: {}", config.address, e);
+                        let address = (config.address.clone(), config.transport);
+                        if let Some((key, config)) = stopped.remove(&address)
+                            && let Ok(bound) = bind_listener(&config).await
+                        {
+                            let task = self.spawn_listener(bound, config.clone());
+                            listeners.insert(key, (config, task));
+                        }
+                        continue;
+                    }
+                },
+            };
+            let task = self.spawn_listener(bound, config.clone());
+            listeners.insert(key, (config, task));
+        }
+    }
+
+    fn spawn_listener(
+        self: &Arc<Self>,
+        bound: BoundListener,
+        config: ListenerConfig,
+    ) -> JoinHandle<()> {
+        let server = self.clone();
+        tokio::spawn(async move {
+            let address = config.address.clone();
+            let result = match bound {
+                BoundListener::Tcp {
+                    listener,
+                    #[cfg(feature = "tls")]
+                    acceptor,
+                } => {
+                    server
+                        .tcp_listener_loop(
+                            listener,
+                            #[cfg(feature = "tls")]
+                            acceptor,
+                            config,
+                        )
+                        .await
+                }
+                BoundListener::Udp(socket) => udp::listener_loop(server, socket, config).await,
+            };
+            if let Err(e) = result {
+                error!(target: "server", "listener at {} failed: {}", address, e);
+            }
+        })
+    }
+
+    async fn tcp_listener_loop(
+        self: Arc<Self>,
+        listener: TcpListener,
+        #[cfg(feature = "tls")] acceptor: Option<TlsAcceptor>,
+        config: ListenerConfig,
+    ) -> Result<()> {
+        info!(target: "server", "listening for {} devices at {} over tcp", config.protocol, config.address);
 
         loop {
-            let (client, client_addr) = listener.accept().await.unwrap();
-            let online_clients = self.online_clients.clone();
-            let verify_timeout = Duration::from_secs(self.settings.verify_timeout);
-
-            let mut client_handler = ClientHandler::new(
-                client,
-                client_addr,
-                self.command_tx.subscribe(),
-                heartbeat_duration,
-                self.settings.output_dir.clone(),
-            );
-            tokio::spawn(async move {
-                // Verify client and add to online clients list
-                let info = time::timeout(verify_timeout, client_handler.verify_client())
-                    .await
-                    .unwrap()
-                    .unwrap();
-                online_clients.write().await.push(info.clone());
-
-                client_handler.run().await;
-
-                // Remove client from online clients list on disconnect
-                online_clients.write().await.retain(|c| c != &info);
-            });
+            let (client, client_addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.wait_for_shutdown() => {
+                    info!(target: "server", "stopped listening at {}", config.address);
+                    return Ok(());
+                }
+            };
+
+            #[cfg(feature = "tls")]
+            if let Some(acceptor) = &acceptor {
+                let server = self.clone();
+                let task = tls::serve_client(
+                    server,
+                    acceptor.clone(),
+                    client,
+                    client_addr,
+                    config.clone(),
+                );
+                tokio::spawn(task);
+                continue;
+            }
+
+            let client_handler = self.create_client_handler(client, client_addr, &config);
+            tokio::spawn(self.clone().serve_client(client_handler, |_| {}));
         }
     }
+
+    fn create_client_handler(
+        &self,
+        client: impl ClientStream + 'static,
+        client_addr: SocketAddr,
+        config: &ListenerConfig,
+    ) -> ClientHandler {
+        ClientHandler::new(
+            client,
+            client_addr,
+            self.sessions.clone(),
+            self.commands.clone(),
+            self.outbox.clone(),
+            self.subscribe_settings(),
+            config,
+        )
+    }
+
+    /// Runs a client until it disconnects, `on_verified` is called once it has logged in.
+    async fn serve_client(
+        self: Arc<Self>,
+        mut client_handler: ClientHandler,
+        on_verified: impl FnOnce(&ClientInfo),
+    ) {
+        let _connection = self.connections.clone().read_owned().await;
+        let verify_timeout = Duration::from_secs(self.settings().verify_timeout);
+
+        // The session is registered as soon as the device logs in
+        let verified = tokio::select! {
+            verified = time::timeout(verify_timeout, client_handler.verify_client()) => verified,
+            _ = self.wait_for_shutdown() => {
+                client_handler.shutdown_client().await;
+                return;
+            }
+        };
+        let info = match verified {
+            Ok(Ok(info)) => info,
+            Ok(Err(e)) => {
+                warn!(target: "server", "{} failed to verify: {}", client_handler, e);
+                client_handler.shutdown_client().await;
+                return;
+            }
+            Err(_) => {
+                warn!(target: "server", "{} timed out before verifying", client_handler);
+                client_handler.shutdown_client().await;
+                return;
+            }
+        };
+        on_verified(&info);
+
+        client_handler.run().await;
+    }
+}
+
+/// Socket of a listener, bound before the listener it replaces is stopped.
+enum BoundListener {
+    Tcp {
+        listener: TcpListener,
+        #[cfg(feature = "tls")]
+        acceptor: Option<TlsAcceptor>,
+    },
+    Udp(UdpSocket),
+}
+
+async fn bind_listener(config: &ListenerConfig) -> Result<BoundListener> {
+    let bound = match config.transport {
+        Transport::Tcp => BoundListener::Tcp {
+            #[cfg(feature = "tls")]
+            acceptor: config.tls.as_ref().map(tls::acceptor).transpose()?,
+            listener: TcpListener::bind(&config.address).await?,
+        },
+        Transport::Udp => BoundListener::Udp(UdpSocket::bind(&config.address).await?),
+    };
+    Ok(bound)
+}
+
+/// Checks what can be checked of a listener without binding its address.
+fn check_listener(config: &ListenerConfig) -> Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.tls {
+        tls::acceptor(tls)?;
+    }
+    #[cfg(not(feature = "tls"))]
+    let _ = config;
+    Ok(())
+}
+
+fn render_command(
+    id: &str,
+    profile: Option<&CommandProfile>,
+    command: &ClientCommand,
+) -> Result<ClientCommand> {
+    let mut command = command.clone();
+    match command.template.take() {
+        Some(call) => {
+            let profile =
+                profile.ok_or(anyhow!("no command profile for the firmware of {}", id))?;
+            command.command = profile.render(&call)?;
+        }
+        None => {
+            if command.command.is_empty() {
+                bail!("empty command");
+            }
+            if profile.is_some_and(|profile| !profile.allow_raw) {
+                bail!("raw commands are not allowed for {}", id);
+            }
+        }
+    }
+
+    // Binary payloads are sent as they are
+    if let Some(profile) = profile
+        && command.encoding.is_text()
+    {
+        if command.terminator.is_none() {
+            command.terminator = profile.terminator.clone();
+        }
+        if command.charset.is_none() {
+            command.charset = profile.charset.clone();
+        }
+    }
+
+    // Invalid payloads fail here instead of in the device's session
+    command.payload()?;
+    Ok(command)
 }
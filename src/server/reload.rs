@@ -0,0 +1,53 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use log::{error, info};
+use tokio::{fs, time};
+
+use super::Server;
+use crate::settings::Settings;
+
+/// How often settings.json is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reloads the settings when settings.json changes, or on SIGHUP on Unix.
+pub async fn reload_loop(server: Arc<Server>) -> Result<()> {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let mut modified = modified_time().await;
+
+    loop {
+        #[cfg(unix)]
+        let hangup_received = hangup.recv();
+        #[cfg(not(unix))]
+        let hangup_received = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = hangup_received => {
+                info!(target: "reload", "received SIGHUP, reloading {}", Settings::FILE_NAME);
+            }
+            _ = time::sleep(POLL_INTERVAL) => {
+                let current = modified_time().await;
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+                info!(target: "reload", "{} changed, reloading", Settings::FILE_NAME);
+            }
+            _ = server.wait_for_shutdown() => return Ok(()),
+        }
+
+        if let Err(e) = server.reload_settings_impl().await {
+            error!(target: "reload", "keeping the current settings, failed to reload {}: {}", Settings::FILE_NAME, e);
+        }
+    }
+}
+
+async fn modified_time() -> Option<SystemTime> {
+    fs::metadata(Settings::FILE_NAME)
+        .await
+        .ok()?
+        .modified()
+        .ok()
+}
@@ -1,15 +1,34 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
-use axum::extract::{Path, State};
-use axum::routing::{get, post};
+use anyhow::{Result, anyhow};
+use axum::extract::{FromRequestParts, Path, Query, State};
+use axum::http::StatusCode;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
 use axum::{Json, Router};
 use chrono::{DateTime, Utc};
+use log::{error, info};
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use uuid::Uuid;
 
 use super::Server;
+use super::bulk::{BulkJobRequest, BulkJobSnapshot};
+use super::scheduler::{Job, JobRequest};
+use crate::client::at::AtResult;
 use crate::client::command::ClientCommand;
-use crate::client::info::RegisteredClientInfo;
+use crate::client::info::{DeviceStatus, RegisteredClientInfo, is_valid_identifier};
+use crate::client::outbox::OutboxItem;
+use crate::client::position::Position;
+use crate::client::protocol::Protocol;
+use crate::client::session::SessionSnapshot;
+use crate::client::signal::{CsqSample, CsqStats, StatsPeriod};
+use crate::client::template::{CommandProfile, TemplateCall};
+use crate::client::tracker::CommandRecord;
+use crate::settings::{ServiceConfig, Settings};
 
 pub trait RestServer {
     async fn serve_rest(self: Arc<Self>) -> Result<()>;
@@ -17,34 +36,114 @@ pub trait RestServer {
 
 impl RestServer for Server {
     async fn serve_rest(self: Arc<Self>) -> Result<()> {
-        let rest_address = &self.settings.rest.address;
-        let listener = tokio::net::TcpListener::bind(rest_address).await.unwrap();
-        axum::serve(listener, router(self.clone())).await?;
-        Ok(())
+        let mut settings = self.subscribe_settings();
+        loop {
+            let config = settings.borrow_and_update().rest.clone();
+            if config.enabled {
+                match tokio::net::TcpListener::bind(&config.address).await {
+                    Ok(listener) => {
+                        info!(target: "rest", "serving REST API at {}", config.address);
+                        // Rebinds once the REST settings change
+                        let server = self.clone();
+                        let mut changes = settings.clone();
+                        let current = config.clone();
+                        axum::serve(listener, router(self.clone()))
+                            .with_graceful_shutdown(async move {
+                                tokio::select! {
+                                    _ = rest_changed(&mut changes, &current) => {}
+                                    _ = server.wait_for_shutdown() => {}
+                                }
+                            })
+                            .await?;
+                        info!(target: "rest", "stopped serving REST API at {}", config.address);
+                    }
+                    Err(e) => {
+                        error!(target: "rest", "failed to bind REST API at {}: {}", config.address, e)
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = rest_changed(&mut settings, &config) => {}
+                _ = self.wait_for_shutdown() => return Ok(()),
+            }
+        }
     }
 }
 
+async fn rest_changed(settings: &mut watch::Receiver<Arc<Settings>>, current: &ServiceConfig) {
+    settings.wait_for(|s| s.rest != *current).await.ok();
+}
+
 fn router(server: Arc<Server>) -> Router {
     Router::new()
         .route("/v1/clients", get(list_all_clients))
         .route("/v1/clients/online", get(list_online_clients))
+        .route("/v1/clients/quarantined", get(list_quarantined_clients))
         .route("/v1/clients/{imei}/info", get(get_client_info))
         .route("/v1/clients/{imei}/log", get(get_client_log))
+        .route("/v1/clients/{imei}/positions", get(get_client_positions))
+        .route("/v1/clients/{imei}/position", get(get_client_position))
+        .route("/v1/clients/{imei}/csq", get(get_client_csq))
+        .route("/v1/clients/{imei}/csq/stats", get(get_client_csq_stats))
         .route("/v1/clients/command", post(send_command))
+        .route("/v1/commands/{id}", get(get_command))
+        .route("/v1/clients/{imei}/at", post(send_at_command))
+        .route("/v1/command_profiles", get(list_command_profiles))
+        .route("/v1/templates/render", post(render_template))
+        .route("/v1/clients/{imei}/outbox", get(get_outbox))
+        .route("/v1/clients/{imei}/outbox/{id}", delete(cancel_outbox_item))
         .route("/v1/clients/{imei}/meta", post(set_meta))
+        .route("/v1/clients/{imei}/approve", post(approve_client))
+        .route("/v1/clients/{imei}/reject", post(reject_client))
+        .route("/v1/clients/{imei}/kick", post(kick_client))
+        .route("/v1/sessions", get(list_sessions))
+        .route("/v1/bulk", get(list_bulk_jobs).post(start_bulk_job))
+        .route("/v1/bulk/{id}", get(get_bulk_job))
+        .route("/v1/bulk/{id}/pause", post(pause_bulk_job))
+        .route("/v1/bulk/{id}/resume", post(resume_bulk_job))
+        .route("/v1/bulk/{id}/cancel", post(cancel_bulk_job))
+        .route("/v1/jobs", get(list_jobs).post(create_job))
+        .route(
+            "/v1/jobs/{id}",
+            get(get_job).put(update_job).delete(delete_job),
+        )
         .with_state(server)
 }
 
+/// The `{imei}` of the path, rejected unless it is a valid device identifier.
+struct Imei(String);
+
+impl<S: Send + Sync> FromRequestParts<S> for Imei {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(imei) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if !is_valid_identifier(&imei) {
+            let message = format!("invalid device identifier {:?}", imei);
+            return Err((StatusCode::BAD_REQUEST, message).into_response());
+        }
+        Ok(Self(imei))
+    }
+}
+
 #[derive(Serialize, Debug)]
 struct ClientInfoResponse {
     pub imei: String,
     pub iccid: String,
     pub fver: String,
 
+    /// Current signal quality when online, the last known one otherwise
     pub csq: Option<i32>,
+    pub last_csq: Option<CsqSample>,
+    pub online: bool,
 
     pub name: Option<String>,
     pub tags: Vec<String>,
+    pub status: DeviceStatus,
+    pub protocol: Option<Protocol>,
 
     pub first_seen: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
@@ -56,9 +155,13 @@ impl From<RegisteredClientInfo> for ClientInfoResponse {
             imei: info.base_info.imei,
             iccid: info.base_info.iccid,
             fver: info.base_info.fver,
-            csq: info.base_info.csq,
+            csq: info.last_csq.map(|sample| sample.csq),
+            last_csq: info.last_csq,
+            online: false,
             name: info.name,
             tags: info.tags,
+            status: info.status,
+            protocol: info.protocol,
             first_seen: info.first_seen,
             last_seen: info.last_seen,
         }
@@ -74,7 +177,10 @@ async fn list_clients(server: Arc<Server>) -> Result<Vec<ClientInfoResponse>> {
             let mut info: ClientInfoResponse = info.into();
 
             let online = online_clients.iter().find(|&c| c.imei == info.imei);
-            info.csq = online.and_then(|c| c.csq);
+            if let Some(online) = online {
+                info.csq = online.csq;
+            }
+            info.online = online.is_some();
             info
         })
         .collect();
@@ -88,13 +194,24 @@ async fn list_all_clients(State(server): State<Arc<Server>>) -> Json<Vec<ClientI
 
 async fn list_online_clients(State(server): State<Arc<Server>>) -> Json<Vec<ClientInfoResponse>> {
     let clients = list_clients(server).await.unwrap_or_default();
-    let clients = clients.into_iter().filter(|c| c.csq.is_some()).collect();
+    let clients = clients.into_iter().filter(|c| c.online).collect();
+    Json(clients)
+}
+
+async fn list_quarantined_clients(
+    State(server): State<Arc<Server>>,
+) -> Json<Vec<ClientInfoResponse>> {
+    let clients = list_clients(server).await.unwrap_or_default();
+    let clients = clients
+        .into_iter()
+        .filter(|c| c.status == DeviceStatus::Quarantined)
+        .collect();
     Json(clients)
 }
 
 async fn get_client_info(
     State(server): State<Arc<Server>>,
-    Path(imei): Path<String>,
+    Imei(imei): Imei,
 ) -> Json<Option<ClientInfoResponse>> {
     let info = RegisteredClientInfo::find(&imei).await;
     if info.is_none() {
@@ -105,16 +222,75 @@ async fn get_client_info(
 
     let online_clients = server.list_online_clients_impl().await;
     let online = online_clients.iter().find(|&c| c.imei == info.imei);
-    info.csq = online.and_then(|c| c.csq);
+    if let Some(online) = online {
+        info.csq = online.csq;
+    }
+    info.online = online.is_some();
 
     Json(Some(info))
 }
 
-async fn get_client_log(State(server): State<Arc<Server>>, Path(imei): Path<String>) -> String {
-    match server.get_client_log_impl(&imei).await {
-        Some(content) => content,
-        None => String::new(),
-    }
+async fn get_client_log(State(server): State<Arc<Server>>, Imei(imei): Imei) -> String {
+    server.get_client_log_impl(&imei).await.unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+struct PositionsQuery {
+    limit: Option<usize>,
+    #[serde(default)]
+    alarms: bool,
+}
+
+async fn get_client_positions(
+    State(server): State<Arc<Server>>,
+    Imei(imei): Imei,
+    Query(query): Query<PositionsQuery>,
+) -> Json<Vec<Position>> {
+    let limit = query.limit.unwrap_or(100);
+    let positions = server
+        .get_client_positions_impl(&imei, limit, query.alarms)
+        .await;
+    Json(positions)
+}
+
+async fn get_client_position(
+    State(server): State<Arc<Server>>,
+    Imei(imei): Imei,
+) -> Json<Option<Position>> {
+    let positions = server.get_client_positions_impl(&imei, 1, false).await;
+    Json(positions.into_iter().next())
+}
+
+#[derive(Deserialize)]
+struct CsqQuery {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+    #[serde(default)]
+    period: StatsPeriod,
+}
+
+async fn get_client_csq(
+    State(server): State<Arc<Server>>,
+    Imei(imei): Imei,
+    Query(query): Query<CsqQuery>,
+) -> Json<Vec<CsqSample>> {
+    let limit = query.limit.unwrap_or(100);
+    let samples = server
+        .get_client_csq_impl(&imei, query.since, query.until, limit)
+        .await;
+    Json(samples)
+}
+
+async fn get_client_csq_stats(
+    State(server): State<Arc<Server>>,
+    Imei(imei): Imei,
+    Query(query): Query<CsqQuery>,
+) -> Json<Vec<CsqStats>> {
+    let stats = server
+        .get_client_csq_stats_impl(&imei, query.since, query.until, query.period)
+        .await;
+    Json(stats)
 }
 
 #[derive(Serialize)]
@@ -122,11 +298,155 @@ struct OperationResponse {
     success: bool,
 }
 
+#[derive(Deserialize)]
+struct WaitQuery {
+    /// Seconds to wait for the devices to answer
+    wait: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SendCommandResponse {
+    success: bool,
+    id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<CommandRecord>,
+}
+
 async fn send_command(
     State(server): State<Arc<Server>>,
-    Json(command): Json<ClientCommand>,
+    Query(query): Query<WaitQuery>,
+    Json(mut command): Json<ClientCommand>,
+) -> Json<SendCommandResponse> {
+    // A caller supplied id could overwrite the record of another command
+    command.id = Uuid::new_v4();
+    let success = server.send_command_impl(&command).await;
+
+    let record = match query.wait {
+        Some(wait) if success => {
+            let wait = Some(Duration::from_secs(wait));
+            server.get_command_impl(command.id, wait).await
+        }
+        _ => None,
+    };
+    Json(SendCommandResponse {
+        success,
+        id: command.id,
+        record,
+    })
+}
+
+async fn get_command(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<WaitQuery>,
+) -> Json<Option<CommandRecord>> {
+    let wait = query.wait.map(Duration::from_secs);
+    Json(server.get_command_impl(id, wait).await)
+}
+
+#[derive(Deserialize)]
+struct AtCommandRequest {
+    command: String,
+    /// Seconds to wait for the response, capped at the command timeout which is also the default
+    wait: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct AtCommandResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    result: Option<AtResult>,
+}
+
+async fn send_at_command(
+    State(server): State<Arc<Server>>,
+    Imei(imei): Imei,
+    Json(request): Json<AtCommandRequest>,
+) -> Json<AtCommandResponse> {
+    let wait = request.wait.map(Duration::from_secs);
+    let result = server
+        .send_at_command_impl(&imei, &request.command, wait)
+        .await;
+    Json(match result {
+        Ok(result) => AtCommandResponse {
+            success: result.response.is_ok(),
+            error: None,
+            result: Some(result),
+        },
+        Err(e) => AtCommandResponse {
+            success: false,
+            error: Some(e.to_string()),
+            result: None,
+        },
+    })
+}
+
+async fn list_command_profiles(
+    State(server): State<Arc<Server>>,
+) -> Json<BTreeMap<String, CommandProfile>> {
+    Json(server.list_command_profiles_impl())
+}
+
+#[derive(Deserialize)]
+struct RenderTemplateRequest {
+    /// Device whose firmware version selects the profile, if `fver` is not given
+    imei: Option<String>,
+    fver: Option<String>,
+    #[serde(flatten)]
+    call: TemplateCall,
+}
+
+#[derive(Serialize)]
+struct RenderTemplateResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn render_template(
+    State(server): State<Arc<Server>>,
+    Json(request): Json<RenderTemplateRequest>,
+) -> Json<RenderTemplateResponse> {
+    let fver = match (request.fver, request.imei) {
+        (Some(fver), _) => Ok(fver),
+        (None, Some(imei)) => server
+            .get_client_fver_impl(&imei)
+            .await
+            .ok_or(anyhow!("unknown device {}", imei)),
+        (None, None) => Err(anyhow!("either imei or fver is required")),
+    };
+
+    let result = fver.and_then(|fver| server.render_template_impl(&fver, &request.call));
+    Json(match result {
+        Ok(command) => RenderTemplateResponse {
+            success: true,
+            command: Some(command),
+            error: None,
+        },
+        Err(e) => RenderTemplateResponse {
+            success: false,
+            command: None,
+            error: Some(e.to_string()),
+        },
+    })
+}
+
+async fn get_outbox(State(server): State<Arc<Server>>, Imei(imei): Imei) -> Json<Vec<OutboxItem>> {
+    Json(server.list_outbox_impl(&imei).await)
+}
+
+async fn cancel_outbox_item(
+    State(server): State<Arc<Server>>,
+    Path((imei, id)): Path<(String, Uuid)>,
 ) -> Json<OperationResponse> {
-    let success = server.send_command_impl(&command);
+    let success = server
+        .cancel_outbox_impl(&imei, id)
+        .await
+        .unwrap_or_default();
     Json(OperationResponse { success })
 }
 
@@ -138,7 +458,7 @@ struct UpdateMetadataRequest {
 
 async fn set_meta(
     State(_server): State<Arc<Server>>,
-    Path(imei): Path<String>,
+    Imei(imei): Imei,
     Json(request): Json<UpdateMetadataRequest>,
 ) -> Json<OperationResponse> {
     let info = RegisteredClientInfo::find(&imei).await;
@@ -157,3 +477,167 @@ async fn set_meta(
     let success = info.save().await.is_ok();
     Json(OperationResponse { success })
 }
+
+async fn set_status(imei: &str, status: DeviceStatus) -> OperationResponse {
+    let Some(mut info) = RegisteredClientInfo::find(imei).await else {
+        return OperationResponse { success: false };
+    };
+
+    info.status = status;
+    let success = info.save().await.is_ok();
+    OperationResponse { success }
+}
+
+async fn approve_client(
+    State(_server): State<Arc<Server>>,
+    Imei(imei): Imei,
+) -> Json<OperationResponse> {
+    Json(set_status(&imei, DeviceStatus::Approved).await)
+}
+
+async fn reject_client(
+    State(server): State<Arc<Server>>,
+    Imei(imei): Imei,
+) -> Json<OperationResponse> {
+    let response = set_status(&imei, DeviceStatus::Rejected).await;
+    // The status is only checked at login, so a connected device is disconnected
+    if response.success {
+        server
+            .sessions
+            .kick(&imei, "rejected through the API")
+            .await;
+    }
+    Json(response)
+}
+
+async fn kick_client(
+    State(server): State<Arc<Server>>,
+    Imei(imei): Imei,
+) -> Json<OperationResponse> {
+    let success = server.kick_client_impl(&imei).await;
+    Json(OperationResponse { success })
+}
+
+async fn list_sessions(State(server): State<Arc<Server>>) -> Json<Vec<SessionSnapshot>> {
+    Json(server.list_sessions_impl().await)
+}
+
+#[derive(Serialize)]
+struct JobResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<Job>,
+}
+
+impl From<Result<Option<Job>>> for JobResponse {
+    fn from(result: Result<Option<Job>>) -> Self {
+        match result {
+            Ok(job) => Self {
+                success: job.is_some(),
+                error: None,
+                job,
+            },
+            Err(e) => Self {
+                success: false,
+                error: Some(e.to_string()),
+                job: None,
+            },
+        }
+    }
+}
+
+async fn list_jobs(State(server): State<Arc<Server>>) -> Json<Vec<Job>> {
+    Json(server.list_jobs_impl().await)
+}
+
+async fn get_job(State(server): State<Arc<Server>>, Path(id): Path<Uuid>) -> Json<Option<Job>> {
+    Json(server.get_job_impl(id).await)
+}
+
+async fn create_job(
+    State(server): State<Arc<Server>>,
+    Json(request): Json<JobRequest>,
+) -> Json<JobResponse> {
+    let result = server.create_job_impl(request).await.map(Some);
+    Json(result.into())
+}
+
+async fn update_job(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<JobRequest>,
+) -> Json<JobResponse> {
+    Json(server.update_job_impl(id, request).await.into())
+}
+
+async fn delete_job(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<Uuid>,
+) -> Json<OperationResponse> {
+    let success = server.delete_job_impl(id).await.unwrap_or_default();
+    Json(OperationResponse { success })
+}
+
+#[derive(Serialize)]
+struct BulkJobResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<BulkJobSnapshot>,
+}
+
+async fn start_bulk_job(
+    State(server): State<Arc<Server>>,
+    Json(request): Json<BulkJobRequest>,
+) -> Json<BulkJobResponse> {
+    Json(match server.start_bulk_job_impl(request).await {
+        Ok(job) => BulkJobResponse {
+            success: true,
+            error: None,
+            job: Some(job),
+        },
+        Err(e) => BulkJobResponse {
+            success: false,
+            error: Some(e.to_string()),
+            job: None,
+        },
+    })
+}
+
+async fn list_bulk_jobs(State(server): State<Arc<Server>>) -> Json<Vec<BulkJobSnapshot>> {
+    Json(server.list_bulk_jobs_impl().await)
+}
+
+async fn get_bulk_job(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<Uuid>,
+) -> Json<Option<BulkJobSnapshot>> {
+    Json(server.get_bulk_job_impl(id).await)
+}
+
+async fn pause_bulk_job(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<Uuid>,
+) -> Json<OperationResponse> {
+    let success = server.pause_bulk_job_impl(id).await;
+    Json(OperationResponse { success })
+}
+
+async fn resume_bulk_job(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<Uuid>,
+) -> Json<OperationResponse> {
+    let success = server.resume_bulk_job_impl(id).await;
+    Json(OperationResponse { success })
+}
+
+async fn cancel_bulk_job(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<Uuid>,
+) -> Json<OperationResponse> {
+    let success = server.cancel_bulk_job_impl(id).await;
+    Json(OperationResponse { success })
+}
@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Notify, RwLock};
+use uuid::Uuid;
+
+use super::Server;
+use crate::client::command::ClientCommand;
+use crate::client::selector::DeviceSelector;
+
+/// Runs kept in the history of each job
+const HISTORY_SIZE: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobSchedule {
+    /// Cron expression in the server's local time, the seconds field is optional
+    Cron(String),
+    EverySec(u64),
+}
+
+impl JobSchedule {
+    fn validate(&self) -> Result<()> {
+        match self {
+            JobSchedule::Cron(expression) => parse_cron(expression).map(|_| ()),
+            JobSchedule::EverySec(0) => bail!("interval must be at least one second"),
+            JobSchedule::EverySec(_) => Ok(()),
+        }
+    }
+
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            JobSchedule::Cron(expression) => {
+                let schedule = parse_cron(expression).ok()?;
+                let next = schedule.after(&after.with_timezone(&Local)).next()?;
+                Some(next.with_timezone(&Utc))
+            }
+            JobSchedule::EverySec(every) => Some(after + TimeDelta::seconds(*every as i64)),
+        }
+    }
+}
+
+fn parse_cron(expression: &str) -> Result<cron::Schedule> {
+    // Standard five field expressions run at the start of the minute
+    let expression = match expression.split_whitespace().count() {
+        5 => format!("0 {}", expression),
+        _ => expression.to_string(),
+    };
+    cron::Schedule::from_str(&expression).map_err(|e| anyhow!("invalid cron expression: {}", e))
+}
+
+/// Job fields set through the REST API.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobRequest {
+    pub name: Option<String>,
+    pub schedule: JobSchedule,
+    #[serde(default)]
+    pub target: Vec<String>,
+    /// Devices with any of these tags are added to the targets when the job runs
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "DeviceSelector::is_empty")]
+    pub select: DeviceSelector,
+    pub command: String,
+    pub ttl_sec: Option<u64>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobRun {
+    pub started_at: DateTime<Utc>,
+    /// Delivery can be followed through `/v1/commands/{id}`
+    pub command_id: Option<Uuid>,
+    pub targets: Vec<String>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub request: JobRequest,
+    pub created_at: DateTime<Utc>,
+    pub next_run_at: Option<DateTime<Utc>>,
+    /// Latest runs, oldest first
+    #[serde(default)]
+    pub history: VecDeque<JobRun>,
+}
+
+impl Job {
+    fn schedule_next(&mut self, after: DateTime<Utc>) {
+        self.next_run_at = match self.request.enabled {
+            true => self.request.schedule.next_after(after),
+            false => None,
+        };
+    }
+
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.next_run_at.is_some_and(|next| next <= now)
+    }
+}
+
+/// Command jobs run on a schedule, stored in `output_dir/jobs.json`.
+pub struct Scheduler {
+    path: PathBuf,
+    jobs: RwLock<Vec<Job>>,
+    changed: Notify,
+}
+
+impl Scheduler {
+    pub fn new(output_dir: &str) -> Self {
+        Self {
+            path: PathBuf::from(output_dir).join("jobs.json"),
+            jobs: RwLock::new(Vec::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    pub async fn load(&self) -> Result<()> {
+        if !fs::try_exists(&self.path).await.unwrap_or(false) {
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(&self.path).await?;
+        let mut jobs: Vec<Job> = serde_json::from_str(&content)?;
+
+        // Runs missed while the server was down happen once, right away
+        let now = Utc::now();
+        for job in &mut jobs {
+            if !job.request.enabled {
+                job.next_run_at = None;
+            } else if job.next_run_at.is_none() {
+                job.schedule_next(now);
+            }
+        }
+
+        *self.jobs.write().await = jobs;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    pub async fn list(&self) -> Vec<Job> {
+        self.jobs.read().await.clone()
+    }
+
+    pub async fn get(&self, id: Uuid) -> Option<Job> {
+        let jobs = self.jobs.read().await;
+        jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    pub async fn create(&self, request: JobRequest) -> Result<Job> {
+        request.schedule.validate()?;
+
+        let now = Utc::now();
+        let mut job = Job {
+            id: Uuid::new_v4(),
+            request,
+            created_at: now,
+            next_run_at: None,
+            history: VecDeque::new(),
+        };
+        job.schedule_next(now);
+
+        let mut jobs = self.jobs.write().await;
+        jobs.push(job.clone());
+        self.save(&jobs).await?;
+        self.changed.notify_one();
+        Ok(job)
+    }
+
+    pub async fn update(&self, id: Uuid, request: JobRequest) -> Result<Option<Job>> {
+        request.schedule.validate()?;
+
+        let mut jobs = self.jobs.write().await;
+        let Some(job) = jobs.iter_mut().find(|job| job.id == id) else {
+            return Ok(None);
+        };
+        job.request = request;
+        job.schedule_next(Utc::now());
+
+        let job = job.clone();
+        self.save(&jobs).await?;
+        self.changed.notify_one();
+        Ok(Some(job))
+    }
+
+    pub async fn delete(&self, id: Uuid) -> Result<bool> {
+        let mut jobs = self.jobs.write().await;
+        let len = jobs.len();
+        jobs.retain(|job| job.id != id);
+        if jobs.len() == len {
+            return Ok(false);
+        }
+
+        self.save(&jobs).await?;
+        self.changed.notify_one();
+        Ok(true)
+    }
+
+    async fn next_run_at(&self) -> Option<DateTime<Utc>> {
+        let jobs = self.jobs.read().await;
+        jobs.iter().filter_map(|job| job.next_run_at).min()
+    }
+
+    /// Jobs due to run, their next run is scheduled before they are returned.
+    async fn take_due(&self) -> Vec<Job> {
+        let now = Utc::now();
+        let mut jobs = self.jobs.write().await;
+        let mut due = Vec::new();
+        for job in jobs.iter_mut().filter(|job| job.is_due(now)) {
+            due.push(job.clone());
+            job.schedule_next(now);
+        }
+        due
+    }
+
+    async fn record(&self, id: Uuid, run: JobRun) -> Result<()> {
+        let mut jobs = self.jobs.write().await;
+        if let Some(job) = jobs.iter_mut().find(|job| job.id == id) {
+            job.history.push_back(run);
+            while job.history.len() > HISTORY_SIZE {
+                job.history.pop_front();
+            }
+        }
+        self.save(&jobs).await
+    }
+
+    async fn save(&self, jobs: &[Job]) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        let temp_path = self.path.with_extension("json.tmp");
+        let mut file = fs::File::create(&temp_path).await?;
+        let data = serde_json::to_string_pretty(jobs)?;
+        file.write_all(data.as_bytes()).await?;
+        file.flush().await?;
+        fs::rename(temp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+/// Runs the jobs of the server's scheduler as they become due.
+pub async fn scheduler_loop(server: Arc<Server>) -> Result<()> {
+    let scheduler = server.scheduler.clone();
+    scheduler.load().await?;
+    info!(target: "scheduler", "loaded {} job(s)", scheduler.list().await.len());
+
+    loop {
+        let changed = scheduler.changed.notified();
+        let delay = scheduler
+            .next_run_at()
+            .await
+            .map(|next| (next - Utc::now()).to_std().unwrap_or_default());
+        tokio::select! {
+            _ = tokio::time::sleep(delay.unwrap_or_default()), if delay.is_some() => {}
+            _ = changed => continue,
+            _ = server.wait_for_shutdown() => return Ok(()),
+        }
+
+        for job in scheduler.take_due().await {
+            let run = run_job(&server, &job).await;
+            if let Err(e) = scheduler.record(job.id, run).await {
+                error!(target: "scheduler", "failed to save run of job {}: {}", job.id, e);
+            }
+        }
+    }
+}
+
+async fn run_job(server: &Server, job: &Job) -> JobRun {
+    let started_at = Utc::now();
+    let request = &job.request;
+
+    let mut command = ClientCommand::new(request.target.clone(), request.command.clone());
+    command.select = request.select.clone();
+    command.select.tags_any.extend(request.tags.iter().cloned());
+    command.ttl_sec = request.ttl_sec;
+
+    // An empty target list would broadcast the command
+    let command = server.resolve_command_impl(&command).await;
+    let targets = command.targets().to_vec();
+    if targets.is_empty() {
+        warn!(target: "scheduler", "job {} has no matching devices", job.id);
+        return JobRun {
+            started_at,
+            command_id: None,
+            targets,
+            success: false,
+            error: Some("no matching devices".to_string()),
+        };
+    }
+
+    debug!(target: "scheduler", "running job {} as command {}", job.id, command.id);
+
+    let success = server.send_command_impl(&command).await;
+    JobRun {
+        started_at,
+        command_id: Some(command.id),
+        targets,
+        success,
+        error: (!success).then(|| "failed to dispatch command".to_string()),
+    }
+}
@@ -0,0 +1,98 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use log::{debug, warn};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig, crypto};
+
+use super::Server;
+use crate::settings::{ListenerConfig, TlsConfig};
+
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let provider = Arc::new(crypto::ring::default_provider());
+
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("failed to load {}: {}", config.cert_path, e))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .map_err(|e| anyhow!("failed to load {}: {}", config.key_path, e))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)
+                .map_err(|e| anyhow!("failed to load {}: {}", path, e))?
+            {
+                roots.add(cert.map_err(|e| anyhow!("failed to load {}: {}", path, e))?)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder.with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Completes the handshake, then serves the client like a plaintext one.
+pub async fn serve_client(
+    server: Arc<Server>,
+    acceptor: TlsAcceptor,
+    client: TcpStream,
+    client_addr: SocketAddr,
+    config: ListenerConfig,
+) {
+    let verify_timeout = Duration::from_secs(server.settings().verify_timeout);
+    let stream = match time::timeout(verify_timeout, acceptor.accept(client)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            warn!(target: "server", "TLS handshake with {} failed: {}", client_addr, e);
+            return;
+        }
+        Err(_) => {
+            warn!(target: "server", "TLS handshake with {} timed out", client_addr);
+            return;
+        }
+    };
+
+    let certified_identifier = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(common_name);
+
+    // The certificate has to name the device it was issued to when client certificates are required
+    let client_auth = config
+        .tls
+        .as_ref()
+        .is_some_and(|tls| tls.client_ca_path.is_some());
+    if client_auth && certified_identifier.is_none() {
+        warn!(target: "server", "client certificate of {} has no common name", client_addr);
+        return;
+    }
+
+    let mut client_handler = server.create_client_handler(stream, client_addr, &config);
+    if let Some(id) = certified_identifier {
+        debug!(target: "server", "{} presented a certificate for {}", client_addr, id);
+        client_handler.set_certified_identifier(id);
+    }
+    server.serve_client(client_handler, |_| {}).await;
+}
+
+fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let name = cert.subject().iter_common_name().next()?;
+    name.as_str().ok().map(str::to_string)
+}
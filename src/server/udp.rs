@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use super::Server;
+use crate::client::framing::Framing;
+use crate::client::info::ClientInfo;
+use crate::client::protocol::Protocol;
+use crate::settings::ListenerConfig;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const SESSION_QUEUE_SIZE: usize = 16;
+
+struct UdpSession {
+    datagrams: mpsc::Sender<Vec<u8>>,
+    imei: Option<String>,
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, UdpSession>>>;
+
+/// Pseudo-session with a single peer, reads yield the datagrams it sent and
+/// writes are sent back to it.
+struct UdpStream {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    datagrams: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl AsyncRead for UdpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pending.is_empty() {
+            match ready!(self.datagrams.poll_recv(cx)) {
+                Some(datagram) => self.pending = datagram,
+                // Session expired or replaced, reported as end of stream
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UdpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, self.peer)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Sessions end when the loop is aborted, as their datagrams come from it.
+pub async fn listener_loop(
+    server: Arc<Server>,
+    socket: UdpSocket,
+    config: ListenerConfig,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    info!(target: "server", "listening for {} devices at {} over udp", config.protocol, config.address);
+
+    // Text devices usually send a single unterminated line per datagram
+    let terminate_lines = config.protocol == Protocol::Text
+        && matches!(config.framing, Framing::Newline | Framing::NewlineOrRead);
+
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    let mut received = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, peer) = socket.recv_from(&mut received).await?;
+        if len == 0 {
+            continue;
+        }
+
+        let mut datagram = received[..len].to_vec();
+        if terminate_lines && datagram.last() != Some(&b'\n') {
+            datagram.push(b'\n');
+        }
+
+        let mut sessions_guard = sessions.lock().unwrap();
+        // Drop sessions whose handler has expired
+        sessions_guard.retain(|_, session| !session.datagrams.is_closed());
+
+        if let Some(session) = sessions_guard.get(&peer) {
+            if let Err(e) = session.datagrams.try_send(datagram) {
+                warn!(target: "server", "dropped datagram from {}: {}", peer, e);
+            }
+            continue;
+        }
+        // Existing sessions keep receiving until they are closed
+        if server.is_shutting_down() {
+            debug!(target: "server", "ignoring datagram from {} while shutting down", peer);
+            continue;
+        }
+
+        debug!(target: "server", "new udp session with {}", peer);
+        let (datagrams_tx, datagrams_rx) = mpsc::channel(SESSION_QUEUE_SIZE);
+        datagrams_tx.try_send(datagram)?;
+        sessions_guard.insert(
+            peer,
+            UdpSession {
+                datagrams: datagrams_tx,
+                imei: None,
+            },
+        );
+        drop(sessions_guard);
+
+        let stream = UdpStream {
+            socket: socket.clone(),
+            peer,
+            datagrams: datagrams_rx,
+            pending: Vec::new(),
+        };
+        let client_handler = server.create_client_handler(stream, peer, &config);
+
+        // A device logging in from a new address replaces its previous session,
+        // so commands are sent to the address it last reported from
+        let sessions = sessions.clone();
+        let on_verified = move |info: &ClientInfo| {
+            let imei = info.identifier();
+            let mut sessions = sessions.lock().unwrap();
+            sessions.retain(|addr, session| {
+                *addr == peer || session.imei.as_deref() != Some(imei.as_str())
+            });
+            if let Some(session) = sessions.get_mut(&peer) {
+                session.imei = Some(imei);
+            }
+        };
+        tokio::spawn(server.clone().serve_client(client_handler, on_verified));
+    }
+}
@@ -1,26 +1,179 @@
-use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Result, bail};
 use serde::{Deserialize, Serialize};
 use tokio::{fs::File, io::AsyncReadExt};
 
+use crate::client::framing::{self, Framing};
+use crate::client::protocol::Protocol;
+use crate::client::template::CommandProfile;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Settings {
-    pub address: String,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Single text listener used by settings files predating `listeners`
+    #[serde(default, skip_serializing)]
+    address: Option<String>,
     #[cfg(feature = "rest")]
     pub rest: ServiceConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub duplicate_login: DuplicateLoginPolicy,
+    /// Command profiles by firmware version, a trailing `*` matches any suffix
+    #[serde(default)]
+    pub command_profiles: BTreeMap<String, CommandProfile>,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub console: ConsoleConfig,
 
     pub heartbeat_sec: u64,
     pub output_dir: String,
     pub verify_timeout: u64,
+    /// How long devices have to answer a command
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout_sec: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
 pub struct ServiceConfig {
     pub enabled: bool,
     pub address: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub unknown_devices: UnknownDevicePolicy,
+    /// Tokens devices must log in with, by IMEI
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShutdownConfig {
+    /// Sent to every connected device before its connection is closed
+    #[serde(default)]
+    pub goodbye_command: Option<String>,
+    /// The server exits once it is reached, even if connections are still closing
+    #[serde(default = "default_shutdown_deadline")]
+    pub deadline_sec: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            goodbye_command: None,
+            deadline_sec: default_shutdown_deadline(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConsoleConfig {
+    /// Reads console commands from stdin, `--headless` turns it off
+    #[serde(default = "default_console_stdin")]
+    pub stdin: bool,
+    /// Unix socket offering the console to `gps_location_server attach`
+    #[serde(default)]
+    pub socket_path: Option<String>,
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        Self {
+            stdin: default_console_stdin(),
+            socket_path: None,
+        }
+    }
+}
+
+/// What happens to devices missing from `registered_infos.json`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownDevicePolicy {
+    #[default]
+    Accept,
+    Reject,
+    /// Registered as quarantined and disconnected until approved
+    Quarantine,
+}
+
+/// What happens when a device logs in while it already has a session.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    #[default]
+    KickOld,
+    RejectNew,
+    /// Commands go to the newest session
+    AllowBoth,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListenerConfig {
+    pub address: String,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub framing: Framing,
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// Only supported when built with the `tls` feature
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// Requires devices to present a certificate issued by this CA,
+    /// the certificate's common name must match the IMEI they log in with
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// Every datagram is handled as data sent over a session with its peer
+    Udp,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+fn default_command_timeout() -> u64 {
+    30
+}
+
+fn default_shutdown_deadline() -> u64 {
+    10
+}
+
+fn default_console_stdin() -> bool {
+    true
+}
+
+fn default_max_frame_size() -> usize {
+    framing::DEFAULT_MAX_FRAME_SIZE
+}
+
 impl Settings {
-    const FILE_NAME: &str = "settings.json";
+    pub const FILE_NAME: &str = "settings.json";
 
     pub async fn load() -> Result<Settings> {
         let mut file = File::open(Self::FILE_NAME).await?;
@@ -28,7 +181,73 @@ impl Settings {
         let mut data = String::new();
         file.read_to_string(&mut data).await?;
 
-        let json: Settings = serde_json::from_str(&data)?;
+        let mut json: Settings = serde_json::from_str(&data)?;
+        if let Some(address) = json.address.take() {
+            json.listeners.push(ListenerConfig {
+                address,
+                transport: Transport::default(),
+                protocol: Protocol::default(),
+                // Deployed devices may still run firmware predating framing
+                framing: Framing::NewlineOrRead,
+                max_frame_size: default_max_frame_size(),
+                tls: None,
+            });
+        }
+        if json.listeners.is_empty() {
+            bail!("no listeners configured in {}", Self::FILE_NAME);
+        }
+        // Serving plaintext in place of a configured TLS listener would go unnoticed
+        #[cfg(not(feature = "tls"))]
+        if let Some(listener) = json.listeners.iter().find(|l| l.tls.is_some()) {
+            bail!(
+                "listener {} configures tls, but the server was built without the tls feature",
+                listener.address
+            );
+        }
+        if let Some(listener) = json
+            .listeners
+            .iter()
+            .find(|l| l.tls.is_some() && l.transport != Transport::Tcp)
+        {
+            bail!(
+                "TLS listener {} must use the tcp transport",
+                listener.address
+            );
+        }
         Ok(json)
     }
+
+    /// Keeps the fields only read at startup from `current`, returns those that differed.
+    pub fn keep_startup_fields(&mut self, current: &Settings) -> Vec<&'static str> {
+        let mut kept = Vec::new();
+        if self.output_dir != current.output_dir {
+            self.output_dir = current.output_dir.clone();
+            kept.push("output_dir");
+        }
+        if self.command_timeout_sec != current.command_timeout_sec {
+            self.command_timeout_sec = current.command_timeout_sec;
+            kept.push("command_timeout_sec");
+        }
+        if self.console != current.console {
+            self.console = current.console.clone();
+            kept.push("console");
+        }
+        kept
+    }
+
+    /// Profile of the firmware version, exact matches win over the longest pattern.
+    pub fn command_profile(&self, fver: &str) -> Option<&CommandProfile> {
+        if let Some(profile) = self.command_profiles.get(fver) {
+            return Some(profile);
+        }
+
+        self.command_profiles
+            .iter()
+            .filter_map(|(pattern, profile)| {
+                let prefix = pattern.strip_suffix('*')?;
+                fver.starts_with(prefix).then_some((prefix.len(), profile))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, profile)| profile)
+    }
 }